@@ -1,15 +1,19 @@
 use snafu::ResultExt;
 use std::path::PathBuf;
 use std::process::Command;
-use url::Url;
 
+use super::control;
 use super::download;
+use super::elasticsearch;
 use super::error;
+use super::process;
 
 pub fn index_bano_region(
     mimirs_dir: PathBuf,
-    es: Url,
+    es: &elasticsearch::Connection,
+    dataset: &str,
     filepath: PathBuf,
+    cancellation: &control::Cancellation,
 ) -> Result<(), error::Error> {
     let mut execpath = mimirs_dir;
     execpath.push("target");
@@ -17,28 +21,29 @@ pub fn index_bano_region(
     execpath.push("bano2mimir");
     // FIXME Need test file exists
     let mut command = Command::new(&execpath);
+    es.configure(&mut command);
     command
-        .arg("--connection-string")
-        .arg(es.as_str())
+        .arg("--dataset")
+        .arg(dataset)
         .arg("--input")
         .arg(filepath.clone());
-    println!("command: {:?}", command);
-    let output = command.output().context(error::IOError {
+    eprintln!("command: {}", es.redact(&format!("{:?}", command)));
+    let output = process::output(&mut command, cancellation).context(error::IOError {
         details: format!(
             "Could not create bano2mimir command using {}",
             execpath.display()
         ),
     })?;
-    if !output.status.success() {
-        Err(error::Error::MiscError {
-            details: format!("=> {}", String::from_utf8(output.stderr).unwrap()),
-        })
-    } else {
-        Ok(())
-    }
+    process::check("bano2mimir", &output, &es.redactor())?;
+    Ok(())
 }
 
-pub fn download_bano_region(working_dir: PathBuf, region: &str) -> Result<PathBuf, error::Error> {
+pub fn download_bano_region(
+    working_dir: PathBuf,
+    region: &str,
+    cancellation: &control::Cancellation,
+    progress: &control::Progress,
+) -> Result<PathBuf, error::Error> {
     let filename = match region.len() {
         1 => format!("bano-0{}.csv", region),
         _ => format!("bano-{}.csv", region),
@@ -54,6 +59,6 @@ pub fn download_bano_region(working_dir: PathBuf, region: &str) -> Result<PathBu
             ),
         })?;
     }
-    let res = download::download(&target, filepath)?;
+    let res = download::download(&target, filepath, cancellation, progress)?;
     Ok(res.0)
 }
@@ -0,0 +1,257 @@
+use futures::stream::Stream;
+#[cfg(feature = "zmq")]
+use futures::stream::StreamExt;
+#[cfg(feature = "zmq")]
+use snafu::ResultExt;
+#[cfg(feature = "zmq")]
+use std::collections::HashMap;
+use std::pin::Pin;
+#[cfg(feature = "zmq")]
+use std::time::Duration;
+
+#[cfg(feature = "zmq")]
+use super::driver::Driver;
+use super::driver::{Envelope, Heartbeat};
+#[cfg(feature = "zmq")]
+use super::endpoint;
+use super::error;
+
+// Follows what drivers publish, for whoever wants to know how jobs are doing: it subscribes,
+// synchronizes with a driver waiting for its subscribers, reconnects after an error, and tells
+// when the job it watches is over.
+
+#[cfg(feature = "zmq")]
+// How long we wait before connecting again after an error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    State {
+        envelope: Envelope,
+        missed: u64, // How many states of the job we missed before this one
+    },
+    Heartbeat(Heartbeat),
+}
+
+pub type Notifications = Pin<Box<dyn Stream<Item = Result<Notification, error::Error>>>>;
+
+#[cfg(feature = "zmq")]
+// Everything published on the topic, by every job, until the stream is dropped.
+pub fn watch(endpoint: &str, topic: &str) -> Notifications {
+    Watcher::new(endpoint, topic).watch()
+}
+
+#[cfg(feature = "zmq")]
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    endpoint: String, // Where states are published, eg tcp://127.0.0.1:5555
+    topic: String,
+    job_id: Option<String>, // Only that job, until it is over
+    sync: Option<String>,   // The endpoint of a driver which waits for us before starting
+}
+
+#[cfg(feature = "zmq")]
+impl Watcher {
+    pub fn new(endpoint: &str, topic: &str) -> Self {
+        Watcher {
+            endpoint: String::from(endpoint),
+            topic: String::from(topic),
+            job_id: None,
+            sync: None,
+        }
+    }
+
+    // The stream ends after that job's last state.
+    pub fn job<S: Into<String>>(mut self, job_id: S) -> Self {
+        self.job_id = Some(job_id.into());
+        self
+    }
+
+    // Tells the driver, on its endpoint, once we receive what it publishes.
+    pub fn synchronize_with(mut self, endpoint: &str) -> Self {
+        self.sync = Some(String::from(endpoint));
+        self
+    }
+
+    pub fn watch(self) -> Notifications {
+        let watch = Watch {
+            watcher: self,
+            socket: None,
+            sequences: HashMap::new(),
+            synchronized: false,
+            over: false,
+        };
+        Box::pin(futures::stream::unfold(watch, |mut watch| async move {
+            watch.next().await.map(|item| (item, watch))
+        }))
+    }
+}
+
+#[cfg(feature = "zmq")]
+type Socket = async_zmq::subscribe::Subscribe;
+
+#[cfg(feature = "zmq")]
+struct Watch {
+    watcher: Watcher,
+    socket: Option<Socket>, // None until we connect, and after an error
+    sequences: HashMap<String, u64>, // The next sequence number expected, for each job
+    synchronized: bool,
+    over: bool, // We saw the last state of the job we watch
+}
+
+#[cfg(feature = "zmq")]
+impl Watch {
+    fn connect(&self) -> Result<Socket, error::Error> {
+        let endpoint = &self.watcher.endpoint;
+        let socket = async_zmq::subscribe(endpoint)
+            .context(error::ZMQSocketError {
+                details: format!("Could not subscribe on {}", endpoint),
+            })?
+            .connect()
+            .context(error::ZMQError {
+                details: format!("Could not connect to {}", endpoint),
+            })?;
+        // The sync and heartbeat topics start with the topic
+        socket
+            .set_subscribe(&self.watcher.topic)
+            .context(error::ZMQSubscribeError {
+                details: format!("Could not subscribe to '{}' topic", self.watcher.topic),
+            })?;
+        Ok(socket)
+    }
+
+    // The next notification, or an error, after which we connect again. None once the job we
+    // watch is over.
+    async fn next(&mut self) -> Option<Result<Notification, error::Error>> {
+        loop {
+            if self.over {
+                return None;
+            }
+            let mut socket = match self.socket.take() {
+                Some(socket) => socket,
+                None => match self.connect() {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        tokio::time::delay_for(RECONNECT_DELAY).await;
+                        return Some(Err(err));
+                    }
+                },
+            };
+            let msg = match socket.next().await {
+                Some(Ok(msg)) => msg,
+                Some(Err(err)) => {
+                    tokio::time::delay_for(RECONNECT_DELAY).await;
+                    return Some(Err(err).context(error::ZMQRecvError {
+                        details: format!("Could not receive from {}", self.watcher.endpoint),
+                    }));
+                }
+                None => {
+                    tokio::time::delay_for(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+            self.socket = Some(socket);
+            let parts: Option<Vec<&str>> = msg.iter().map(|part| part.as_str()).collect();
+            let (topic, payload) = match parts.as_deref() {
+                Some([topic, payload]) => (*topic, *payload),
+                Some(_) => {
+                    return Some(Err(error::Error::MiscError {
+                        details: format!(
+                            "Expected a topic and a payload from {}, received {} part(s)",
+                            self.watcher.endpoint,
+                            msg.len()
+                        ),
+                    }));
+                }
+                None => {
+                    return Some(Err(error::Error::MiscError {
+                        details: format!(
+                            "Received a message which is not UTF-8 from {}",
+                            self.watcher.endpoint
+                        ),
+                    }));
+                }
+            };
+            if let Some(item) = self.notification(topic, payload).await {
+                return Some(item);
+            }
+        }
+    }
+
+    // What the message means for us, if anything.
+    async fn notification(
+        &mut self,
+        topic: &str,
+        payload: &str,
+    ) -> Option<Result<Notification, error::Error>> {
+        if topic == Driver::sync_topic(&self.watcher.topic) {
+            return self.synchronize().await.err().map(Err);
+        }
+        if topic == Driver::heartbeat_topic(&self.watcher.topic) {
+            return match serde_json::from_str::<Heartbeat>(payload) {
+                Ok(heartbeat) if self.watches(&heartbeat.job_id) => {
+                    Some(Ok(Notification::Heartbeat(heartbeat)))
+                }
+                Ok(_) => None,
+                Err(err) => Some(Err(err).context(error::SerdeJSONError {
+                    details: String::from("Could not deserialize heartbeat"),
+                })),
+            };
+        }
+        if topic != self.watcher.topic {
+            return None;
+        }
+        let envelope = match serde_json::from_str::<Envelope>(payload) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                return Some(Err(err).context(error::SerdeJSONError {
+                    details: String::from("Could not deserialize state"),
+                }));
+            }
+        };
+        if !self.watches(&envelope.job_id) {
+            return None;
+        }
+        let expected = self
+            .sequences
+            .get(&envelope.job_id)
+            .cloned()
+            .unwrap_or(envelope.sequence);
+        self.sequences
+            .insert(envelope.job_id.clone(), envelope.sequence + 1);
+        if self.watcher.job_id.is_some() && envelope.state.kind().is_terminal() {
+            self.over = true;
+        }
+        Some(Ok(Notification::State {
+            missed: envelope.sequence.saturating_sub(expected),
+            envelope,
+        }))
+    }
+
+    fn watches(&self, job_id: &str) -> bool {
+        self.watcher
+            .job_id
+            .as_ref()
+            .map(|watched| watched == job_id)
+            .unwrap_or(true)
+    }
+
+    // A hello on the sync topic means we are subscribed: the driver can start.
+    async fn synchronize(&mut self) -> Result<(), error::Error> {
+        let endpoint = match (&self.watcher.sync, self.synchronized) {
+            (Some(endpoint), false) => endpoint.clone(),
+            _ => return Ok(()),
+        };
+        let response: endpoint::Response =
+            endpoint::request(&endpoint, &endpoint::Request::Sync).await?;
+        match response {
+            endpoint::Response::Ready => {
+                self.synchronized = true;
+                Ok(())
+            }
+            response => Err(error::Error::MiscError {
+                details: format!("Unexpected synchronization response {:?}", response),
+            }),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How often a sleep checks whether it was cancelled.
+const SLEEP_SLICE: Duration = Duration::from_millis(100);
+
+// Commands clients send to a running driver, through its endpoint.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Command {
+    Cancel,
+    Pause,
+    Resume,
+}
+
+// Shared between the driver, the endpoint, and the work in progress (downloads, external
+// commands), which checks it regularly and stops as soon as it is set.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    flag: Arc<AtomicBool>,
+    parent: Option<Arc<AtomicBool>>, // Cancelling the parent cancels this one too
+}
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Cancellation::default()
+    }
+
+    // A cancellation of part of the work, eg a phase, which can be cancelled on its own.
+    pub fn child(&self) -> Self {
+        Cancellation {
+            flag: Arc::new(AtomicBool::new(false)),
+            parent: Some(self.flag.clone()),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+            || self
+                .parent
+                .as_ref()
+                .map(|parent| parent.load(Ordering::SeqCst))
+                .unwrap_or(false)
+    }
+
+    // Sleeps, unless cancelled in the meantime. Returns false if it was.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let end = Instant::now() + duration;
+        while !self.is_cancelled() {
+            let now = Instant::now();
+            if now >= end {
+                return true;
+            }
+            std::thread::sleep(SLEEP_SLICE.min(end - now));
+        }
+        false
+    }
+}
+
+// How far along the work in progress is, eg how many bytes were downloaded, for those who watch
+// it. Shared like the cancellation.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    done: Arc<AtomicU64>,
+    total: Arc<AtomicU64>, // 0 while we don't know
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Amount {
+    pub done: u64,
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress::default()
+    }
+
+    // Starts over, eg for the next file.
+    pub fn start(&self, total: Option<u64>) {
+        self.done.store(0, Ordering::SeqCst);
+        self.total.store(total.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, amount: u64) {
+        self.done.fetch_add(amount, Ordering::SeqCst);
+    }
+
+    // None until the work reports anything.
+    pub fn amount(&self) -> Option<Amount> {
+        let done = self.done.load(Ordering::SeqCst);
+        let total = self.total.load(Ordering::SeqCst);
+        if done == 0 && total == 0 {
+            return None;
+        }
+        Some(Amount {
+            done,
+            total: if total > 0 { Some(total) } else { None },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleep_stops_when_cancelled() {
+        let cancellation = Cancellation::new();
+        assert!(cancellation.sleep(Duration::from_millis(10)));
+
+        let child = cancellation.child();
+        let cancel = cancellation.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let started = Instant::now();
+        assert!(!child.sleep(Duration::from_secs(60)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+    }
+}
@@ -1,14 +1,19 @@
 use snafu::ResultExt;
 use std::path::PathBuf;
 use std::process::Command;
-use url::Url;
 
+use super::control;
+use super::elasticsearch;
 use super::error;
+use super::process;
+use super::redact;
 
 pub fn index_cosmogony_region(
     mimirs_dir: PathBuf,
-    es: Url,
+    es: &elasticsearch::Connection,
+    dataset: &str,
     filepath: PathBuf,
+    cancellation: &control::Cancellation,
 ) -> Result<(), error::Error> {
     let mut execpath = mimirs_dir;
     execpath.push("target");
@@ -16,25 +21,21 @@ pub fn index_cosmogony_region(
     execpath.push("cosmogony2mimir");
     // FIXME Need test file exists
     let mut command = Command::new(&execpath);
+    es.configure(&mut command);
     command
-        .arg("--connection-string")
-        .arg(es.as_str())
+        .arg("--dataset")
+        .arg(dataset)
         .arg("--input")
         .arg(filepath.clone());
-    println!("command: {:?}", command);
-    let output = command.output().context(error::IOError {
+    eprintln!("command: {}", es.redact(&format!("{:?}", command)));
+    let output = process::output(&mut command, cancellation).context(error::IOError {
         details: format!(
             "Could not create cosmogony2mimir command using {}",
             execpath.display()
         ),
     })?;
-    if !output.status.success() {
-        Err(error::Error::MiscError {
-            details: format!("=> {}", String::from_utf8(output.stderr).unwrap()),
-        })
-    } else {
-        Ok(())
-    }
+    process::check("cosmogony2mimir", &output, &es.redactor())?;
+    Ok(())
 }
 
 pub fn generate_cosmogony(
@@ -42,6 +43,7 @@ pub fn generate_cosmogony(
     working_dir: PathBuf,
     inputpath: PathBuf,
     region: &str,
+    cancellation: &control::Cancellation,
 ) -> Result<PathBuf, error::Error> {
     let filename = format!("{}.json.gz", region);
     let mut outputpath = working_dir;
@@ -68,18 +70,13 @@ pub fn generate_cosmogony(
         .arg(inputpath.clone())
         .arg("--output")
         .arg(outputpath.clone());
-    println!("command: {:?}", command);
-    let output = command.output().context(error::IOError {
+    eprintln!("command: {}", redact::redact(&format!("{:?}", command)));
+    let output = process::output(&mut command, cancellation).context(error::IOError {
         details: format!(
             "Could not create cosmogony command using {}",
             execpath.display()
         ),
     })?;
-    if !output.status.success() {
-        Err(error::Error::MiscError {
-            details: format!("=> {}", String::from_utf8(output.stderr).unwrap()),
-        })
-    } else {
-        Ok(outputpath)
-    }
+    process::check("cosmogony", &output, &redact::Redactor::new())?;
+    Ok(outputpath)
 }
@@ -1,3 +1,4 @@
+use super::control;
 use super::error;
 use snafu::ResultExt;
 use std::fs;
@@ -5,7 +6,12 @@ use std::io::prelude::*;
 use std::path::PathBuf;
 use url::Url;
 
-pub fn download(link: &str, download_path: PathBuf) -> Result<(PathBuf, usize), error::Error> {
+pub fn download(
+    link: &str,
+    download_path: PathBuf,
+    cancellation: &control::Cancellation,
+    progress: &control::Progress,
+) -> Result<(PathBuf, usize), error::Error> {
     let mut download_path = download_path;
     // checks if the download path exists, and tries to create the folders if it doesn't
     if !download_path.exists() {
@@ -16,7 +22,7 @@ pub fn download(link: &str, download_path: PathBuf) -> Result<(PathBuf, usize),
 
     let file = get_filename_from_url(link)?;
 
-    download_path.push(format!("{}", file));
+    download_path.push(file);
 
     if download_path.exists() {
         return Ok((download_path, 0));
@@ -30,13 +36,21 @@ pub fn download(link: &str, download_path: PathBuf) -> Result<(PathBuf, usize),
     if resp.status().is_success() {
         let chunk_size = 1024usize;
         let mut buffer: Vec<u8> = Vec::new();
+        progress.start(resp.content_length());
 
         loop {
+            // Nothing is written to disk until the download completes, so we can just stop
+            if cancellation.is_cancelled() {
+                return Err(error::Error::MiscError {
+                    details: format!("Download of {} cancelled", link),
+                });
+            }
             let mut small_buffer = vec![0; chunk_size];
             let small_buffer_read = resp.read(&mut small_buffer[..]).context(error::IOError {
                 details: "Could not read buffer",
             })?;
             small_buffer.truncate(small_buffer_read);
+            progress.advance(small_buffer_read as u64);
 
             match small_buffer.is_empty() {
                 true => break,
@@ -70,11 +84,11 @@ pub fn get_filename_from_url(link: &str) -> Result<String, error::Error> {
         .ok_or(error::Error::MiscError {
             details: format!("There is such thing as cannot-be-a-base URL... {}", link),
         })?
-        .last()
+        .next_back()
         .ok_or(error::Error::MiscError {
             details: format!("There is such thing as cannot-be-a-base URL... {}", link),
         })?;
-    if last.len() == 0 {
+    if last.is_empty() {
         Ok(String::from("foo"))
     } else {
         Ok(String::from(last))
@@ -1,27 +1,76 @@
-use async_zmq::{Message, MultipartIter, SinkExt};
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use std::collections::VecDeque;
 use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
-use url::Url;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot};
 
 use super::bano;
+use super::control;
 use super::cosmogony;
+use super::elasticsearch;
+use super::endpoint;
 use super::error;
+use super::fsm;
+use super::history;
+use super::journal;
+use super::notify;
 use super::ntfs;
 use super::osm;
+use super::recovery;
+use super::redact;
+use super::scheduler;
+use super::timeout;
+use super::webhook;
 
 // From https://gist.github.com/anonymous/ee3e4df093c136ced7b394dc7ffb78e1
 
+// How long we wait between two checks of Elasticsearch.
+const CLUSTER_CHECK_DELAY: Duration = Duration::from_secs(30);
+
+// While waiting for subscribers, how often we publish a hello on the sync topic.
+const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+// While a phase runs, how often we publish a heartbeat, and how often the watchdog checks it.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// The phases of a job, which may be tried again after an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Phase {
+    Download,
+    Processing,
+    Indexing,
+    Validation,
+}
+
+impl Phase {
+    // From its name on the command line, eg 'indexing'.
+    pub fn parse(name: &str) -> Option<Phase> {
+        match name.to_lowercase().as_str() {
+            "download" => Some(Phase::Download),
+            "processing" => Some(Phase::Processing),
+            "indexing" => Some(Phase::Indexing),
+            "validation" => Some(Phase::Validation),
+            _ => None,
+        }
+    }
+}
+
+// The states of a phase in progress carry the attempt (starting at 1), and its error state how
+// many attempts were made.
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum State {
     NotAvailable,
     DownloadingInProgress {
         started_at: SystemTime,
+        attempt: u32,
     },
     DownloadingError {
-        details: String,
+        error: error::Report,
+        attempts: u32,
     },
     Downloaded {
         file_path: PathBuf,
@@ -30,260 +79,586 @@ pub enum State {
     ProcessingInProgress {
         file_path: PathBuf,
         started_at: SystemTime,
+        attempt: u32,
     },
     ProcessingError {
-        details: String,
+        file_path: PathBuf, // What we were processing, to try again
+        error: error::Report,
+        attempts: u32,
     },
     Processed {
         file_path: PathBuf,
         duration: Duration,
     },
+    WaitingForCluster {
+        file_path: PathBuf,
+        details: String,
+        attempts: u32, // Checks of the cluster
+        attempt: u32,  // Of indexing, once the cluster is ready
+    },
     IndexingInProgress {
         file_path: PathBuf,
         started_at: SystemTime,
+        attempt: u32,
     },
     IndexingError {
-        details: String,
+        file_path: PathBuf, // What we were indexing, to try again
+        error: error::Report,
+        attempts: u32,
     },
     Indexed {
         duration: Duration,
     },
-    ValidationInProgress,
+    ValidationInProgress {
+        attempt: u32,
+    },
     ValidationError {
+        error: error::Report,
+        attempts: u32,
+    },
+    Available {
+        index: String,
+        alias: String,
+    },
+    // The rollback states carry the error which caused the rollback
+    RollbackInProgress {
+        cause: error::Report,
+        attempts: u32,
+    },
+    RollbackError {
         details: String,
+        cause: error::Report,
+        attempts: u32,
+    },
+    RolledBack {
+        indices: Vec<String>,
+        cause: error::Report,
+        attempts: u32,
+    },
+    // The job gave up, after it undid what it could. Unlike NotAvailable, which means it didn't
+    // start. The error tells in which phase.
+    Failed {
+        error: error::Report,
+        attempts: u32,
     },
-    Available,
+    Paused {
+        state: Box<State>, // Where we resume from
+    },
+    Cancelled,
     Failure(String),
 }
 
+impl State {
+    // Returns the state with secrets masked in the free text it carries (error details, ...),
+    // so that it can be safely published.
+    pub fn redact(&self, redactor: &redact::Redactor) -> State {
+        match self {
+            State::DownloadingError { error, attempts } => State::DownloadingError {
+                error: error.redact(redactor),
+                attempts: *attempts,
+            },
+            State::ProcessingError {
+                file_path,
+                error,
+                attempts,
+            } => State::ProcessingError {
+                file_path: file_path.clone(),
+                error: error.redact(redactor),
+                attempts: *attempts,
+            },
+            State::WaitingForCluster {
+                file_path,
+                details,
+                attempts,
+                attempt,
+            } => State::WaitingForCluster {
+                file_path: file_path.clone(),
+                details: redactor.redact(details),
+                attempts: *attempts,
+                attempt: *attempt,
+            },
+            State::IndexingError {
+                file_path,
+                error,
+                attempts,
+            } => State::IndexingError {
+                file_path: file_path.clone(),
+                error: error.redact(redactor),
+                attempts: *attempts,
+            },
+            State::ValidationError { error, attempts } => State::ValidationError {
+                error: error.redact(redactor),
+                attempts: *attempts,
+            },
+            State::RollbackInProgress { cause, attempts } => State::RollbackInProgress {
+                cause: cause.redact(redactor),
+                attempts: *attempts,
+            },
+            State::RollbackError {
+                details,
+                cause,
+                attempts,
+            } => State::RollbackError {
+                details: redactor.redact(details),
+                cause: cause.redact(redactor),
+                attempts: *attempts,
+            },
+            State::RolledBack {
+                indices,
+                cause,
+                attempts,
+            } => State::RolledBack {
+                indices: indices.clone(),
+                cause: cause.redact(redactor),
+                attempts: *attempts,
+            },
+            State::Failed { error, attempts } => State::Failed {
+                error: error.redact(redactor),
+                attempts: *attempts,
+            },
+            State::Paused { state } => State::Paused {
+                state: Box::new(state.redact(redactor)),
+            },
+            State::Failure(details) => State::Failure(redactor.redact(details)),
+            state => state.clone(),
+        }
+    }
+
+    // The phase whose work is done in that state, if any. Waiting for the cluster is part of
+    // indexing, so it gets heartbeats, and counts against the indexing timeout.
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            State::DownloadingInProgress { .. } => Some(Phase::Download),
+            State::ProcessingInProgress { .. } => Some(Phase::Processing),
+            State::WaitingForCluster { .. } | State::IndexingInProgress { .. } => {
+                Some(Phase::Indexing)
+            }
+            State::ValidationInProgress { .. } => Some(Phase::Validation),
+            _ => None,
+        }
+    }
+}
+
+// What we actually publish: the state, with enough context for subscribers watching several jobs
+// to tell them apart, and to detect missed notifications.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Envelope {
+    pub job_id: String,
+    pub sequence: u64, // Starts at 0, and increments with each state published for the job
+    pub timestamp: SystemTime,
+    pub data_source: String,
+    pub region: String,
+    pub index_type: String,
+    pub version: String, // Version of the FSM
+    pub state: State,
+}
+
+// Published on the heartbeat topic while a phase runs, so that subscribers can tell a long phase
+// from a driver which is gone.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Heartbeat {
+    pub job_id: String,
+    pub sequence: u64, // Of the state in progress
+    pub timestamp: SystemTime,
+    pub phase: Phase,
+    pub elapsed: Duration,
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub progress: Option<control::Amount>, // eg bytes downloaded, if the phase reports it
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
-enum Event {
+pub enum Event {
     Download,
-    DownloadingError(String),
+    DownloadingError(error::Report),
     DownloadingComplete(PathBuf, Duration),
     Process(PathBuf),
-    ProcessingError(String),
+    ProcessingError(error::Report),
     ProcessingComplete(PathBuf, Duration),
+    ClusterUnavailable(PathBuf, String),
     Index(PathBuf),
-    IndexingError(String),
+    IndexingError(error::Report),
     IndexingComplete(Duration),
     Validate,
-    ValidationError(String),
-    ValidationComplete,
-    Reset,
+    ValidationError(error::Report),
+    ValidationComplete(String, String),
+    Rollback,
+    RollbackError(String),
+    RollbackComplete(Vec<String>),
+    Timeout(Duration), // The phase in progress took longer than that
+    Fail,              // Give up on the job
+    Retry,             // Try the phase which failed again
+    Pause,
+    Resume,
+    Cancel,
+}
+
+impl From<control::Command> for Event {
+    fn from(command: control::Command) -> Self {
+        match command {
+            control::Command::Cancel => Event::Cancel,
+            control::Command::Pause => Event::Pause,
+            control::Command::Resume => Event::Resume,
+        }
+    }
 }
 
 pub struct Driver {
+    job_id: String,          // Identifies the job in notifications
+    sequence: u64,           // Sequence number of the next notification
     state: State,            // Current state of the FSM
     working_dir: PathBuf,    // Where all the files will go (download, processed, ...)
+    journal_dir: PathBuf,    // Where we record the states, to resume the job
+    history: PathBuf,        // Where we record the job once it is over
     mimirs_dir: PathBuf,     // Where we can find executables XXX2mimir
     cosmogony_dir: PathBuf,  // Where we can find cosmogony
     events: VecDeque<Event>, // A queue of events
-    es: Url,                 // How we connect to elasticsearch
     index_type: String,      // eg admin, streets, addresses, ...
     data_source: String,     // eg OSM, BANO, ...
     region: String,          // The region we need to index
+    dataset: String,         // The mimir dataset, derived from data source and region
     topic: String,           // The topic we need to broadcast.
-    publish: async_zmq::publish::Publish<std::vec::IntoIter<Message>, Message>,
+    // How we connect to elasticsearch
+    es: elasticsearch::Connection,
+    // Elasticsearch before indexing, so we can rollback
+    snapshot: Option<elasticsearch::Snapshot>,
+    // Masks secrets in what we print and publish
+    redactor: redact::Redactor,
+    publish: Publisher,
+    // Answers requests, for a standalone driver, until the driver runs it in drive()
+    #[cfg(feature = "zmq")]
+    endpoint: Option<endpoint::Endpoint>,
+    status: Arc<Mutex<endpoint::Status>>, // Shared with the endpoint
+    synced: mpsc::UnboundedReceiver<()>,  // Notified by the endpoint for each subscriber ready
+    cancellation: control::Cancellation,  // Set by the endpoint, checked by the work in progress
+    work: control::Cancellation,          // Cancels the phase in progress only, on timeout
+    // When the phase in progress started, over all the states it runs in
+    phase_started: Option<(Phase, Instant)>,
+    progress: control::Progress, // Of the phase in progress, reported in heartbeats
+    subscribers: usize,          // How many subscribers we wait for before starting
+    // Forwarded by the endpoint
+    commands: mpsc::UnboundedReceiver<control::Command>,
+    // How many jobs can be in a phase at the same time, shared with the other drivers
+    phases: Arc<scheduler::PhaseLimits>,
+    transitions: Vec<fsm::Transition>, // The transition table of the FSM
+    recovery: recovery::Policy,        // Which phases we try again after an error
+    timeouts: timeout::Timeouts,       // How long each phase may run
+    webhooks: Vec<webhook::Webhook>,   // Posted to when the job reaches some states
+    deliveries: Vec<tokio::task::JoinHandle<()>>, // Of webhooks, which we wait for at the end
+    lock: Option<journal::Lock>,       // On the journal, while we drive
+}
+
+// Where drivers publish their states. A server shares it between all its drivers.
+pub type Publisher = notify::Publisher;
+
+// Distinguishes jobs started in the same process at the same time.
+static JOB_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// A new job id, which is unique for this process.
+pub fn new_job_id(data_source: &str, region: &str, index_type: &str) -> String {
+    format!(
+        "{}-{}-{}-{}-{}",
+        elasticsearch::dataset_name(data_source, region),
+        index_type,
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis(),
+        JOB_COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
 }
 
 impl Driver {
+    // A standalone driver, which publishes its states with the given notifier. Over ZeroMQ, it
+    // publishes on the given port, and answers requests on the next one. Otherwise, no one can
+    // synchronize with it, or control it.
     pub fn new<S: Into<String>>(
         index_type: S,
         data_source: S,
         region: S,
+        es: elasticsearch::Connection,
         topic: String,
+        notifier: &notify::Config,
         port: u32,
     ) -> Result<Self, error::Error> {
-        let zmq_endpoint = format!("tcp://127.0.0.1:{}", port);
-        let zmq = async_zmq::publish(&zmq_endpoint)
-            .context(error::ZMQSocketError {
-                details: format!("Could not publish on endpoint '{}'", zmq_endpoint),
-            })?
-            .bind()
-            .context(error::ZMQError {
-                details: String::from("Could not bind socket for publication"),
-            })?;
-        Ok(Driver {
+        let publish = notifier.open(port)?;
+        let (mut driver, handle) =
+            Driver::with_publisher(index_type, data_source, region, es, topic, publish);
+        if notifier.is_zmq() {
+            driver.listen(port + 1, handle)?;
+        }
+        Ok(driver)
+    }
+
+    // Subscribers synchronize with us, query our status, and control us, on that port.
+    #[cfg(feature = "zmq")]
+    fn listen(&mut self, port: u32, handle: endpoint::Handle) -> Result<(), error::Error> {
+        let (synced_tx, synced) = mpsc::unbounded_channel();
+        let endpoint =
+            endpoint::Endpoint::new(&format!("tcp://127.0.0.1:{}", port), handle, synced_tx)?;
+        self.endpoint = Some(endpoint);
+        self.synced = synced;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "zmq"))]
+    fn listen(&mut self, port: u32, _handle: endpoint::Handle) -> Result<(), error::Error> {
+        Err(error::Error::MiscError {
+            details: format!(
+                "Could not answer requests on port {}, built without ZeroMQ support",
+                port
+            ),
+        })
+    }
+
+    // A driver publishing on a shared socket, and controlled through the returned handle.
+    pub fn with_publisher<S: Into<String>>(
+        index_type: S,
+        data_source: S,
+        region: S,
+        es: elasticsearch::Connection,
+        topic: String,
+        publish: Publisher,
+    ) -> (Self, endpoint::Handle) {
+        let status = Arc::new(Mutex::new(endpoint::Status::default()));
+        let (_, synced) = mpsc::unbounded_channel();
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let cancellation = control::Cancellation::new();
+        let handle = endpoint::Handle {
+            status: status.clone(),
+            commands: commands_tx,
+            cancellation: cancellation.clone(),
+        };
+        let data_source = data_source.into();
+        let region = region.into();
+        let dataset = elasticsearch::dataset_name(&data_source, &region);
+        let redactor = es.redactor();
+        let index_type = index_type.into();
+        let job_id = new_job_id(&data_source, &region, &index_type);
+        let driver = Driver {
+            job_id,
+            sequence: 0,
             state: State::NotAvailable,
             working_dir: PathBuf::from("./work"),
+            journal_dir: PathBuf::from(journal::DIR),
+            history: PathBuf::from(history::PATH),
             mimirs_dir: PathBuf::from("/home/matt/lab/rust/kisio/mimirsbrunn"),
             cosmogony_dir: PathBuf::from("/home/matt/lab/rust/kisio/cosmogony"),
             events: VecDeque::new(),
-            es: Url::parse("http://localhost:9200").unwrap(),
-            index_type: index_type.into(),
-            data_source: data_source.into(),
-            region: region.into(),
+            index_type,
+            data_source,
+            region,
+            dataset,
             topic,
-            publish: zmq,
-        })
+            es,
+            snapshot: None,
+            redactor,
+            publish,
+            #[cfg(feature = "zmq")]
+            endpoint: None,
+            status,
+            synced,
+            work: cancellation.child(),
+            phase_started: None,
+            progress: control::Progress::new(),
+            cancellation,
+            subscribers: 0,
+            commands,
+            phases: Arc::new(scheduler::PhaseLimits::default()),
+            transitions: fsm::table(),
+            recovery: recovery::Policy::default(),
+            timeouts: timeout::Timeouts::default(),
+            webhooks: Vec::new(),
+            deliveries: Vec::new(),
+            lock: None,
+        };
+        (driver, handle)
     }
-    async fn next(&mut self, event: Event) {
-        match (&self.state, event) {
-            (State::NotAvailable, Event::Download) => {
-                self.state = State::DownloadingInProgress {
-                    started_at: SystemTime::now(),
-                };
-            }
-            (State::DownloadingInProgress { .. }, Event::DownloadingError(ref d)) => {
-                self.state = State::DownloadingError {
-                    details: String::from(d.as_str()),
-                };
-            }
-            (State::DownloadingInProgress { .. }, Event::DownloadingComplete(ref p, ref d)) => {
-                self.state = State::Downloaded {
-                    file_path: p.clone(),
-                    duration: d.clone(),
-                }
-            }
-            (State::DownloadingError { .. }, Event::Reset) => {
-                self.state = State::NotAvailable;
-            }
-            (State::Downloaded { .. }, Event::Process(ref p)) => {
-                self.state = State::ProcessingInProgress {
-                    file_path: p.clone(),
-                    started_at: SystemTime::now(),
-                };
-            }
-            (State::ProcessingInProgress { .. }, Event::ProcessingError(d)) => {
-                self.state = State::ProcessingError { details: d }
-            }
-            (State::ProcessingError { .. }, Event::Reset) => {
-                self.state = State::NotAvailable;
-            }
-            (State::ProcessingInProgress { .. }, Event::ProcessingComplete(ref p, ref d)) => {
-                self.state = State::Processed {
-                    file_path: p.clone(),
-                    duration: d.clone(),
-                };
-            }
-            (State::Processed { .. }, Event::Index(ref p)) => {
-                self.state = State::IndexingInProgress {
-                    file_path: p.clone(),
-                    started_at: SystemTime::now(),
-                };
-            }
-            (State::Downloaded { .. }, Event::Index(ref p)) => {
-                self.state = State::IndexingInProgress {
-                    file_path: p.clone(),
-                    started_at: SystemTime::now(),
-                };
-            }
-            (State::IndexingInProgress { .. }, Event::IndexingError(d)) => {
-                self.state = State::IndexingError { details: d }
-            }
-            (State::IndexingError { .. }, Event::Reset) => {
-                self.state = State::NotAvailable;
-            }
-            (State::IndexingInProgress { .. }, Event::IndexingComplete(ref d)) => {
-                self.state = State::Indexed {
-                    duration: d.clone(),
-                };
-            }
-            (State::Indexed { .. }, Event::Validate) => {
-                self.state = State::ValidationInProgress;
-            }
-            (State::ValidationInProgress, Event::ValidationError(d)) => {
-                self.state = State::ValidationError { details: d }
-            }
-            (State::ValidationError { .. }, Event::Reset) => {
-                self.state = State::NotAvailable;
-            }
-            (State::ValidationInProgress, Event::ValidationComplete) => {
-                self.state = State::Available;
-            }
-            (s, e) => {
-                self.state = State::Failure(
-                    format!("Wrong state, event combination: {:#?} {:#?}", s, e).to_string(),
-                )
-            }
-        }
+
+    // The driver won't start until that many subscribers have synchronized, so that they don't
+    // miss any state.
+    pub fn expect_subscribers(&mut self, subscribers: usize) {
+        self.subscribers = subscribers;
+    }
+
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    // For a job which got its id before the driver was created, eg when it was queued.
+    pub fn set_job_id<S: Into<String>>(&mut self, job_id: S) {
+        self.job_id = job_id.into();
+    }
+
+    // The journal and the history go there too.
+    pub fn set_working_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.working_dir = dir.into();
+        self.journal_dir = self.working_dir.join("journal");
+        self.history = self.working_dir.join("history.jsonl");
+    }
+
+    pub fn set_recovery(&mut self, recovery: recovery::Policy) {
+        self.recovery = recovery;
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: timeout::Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn set_webhooks(&mut self, webhooks: Vec<webhook::Webhook>) {
+        self.webhooks = webhooks;
     }
 
-    pub async fn run(&mut self) {
+    pub fn set_phase_limits(&mut self, phases: Arc<scheduler::PhaseLimits>) {
+        self.phases = phases;
+    }
+
+    // The topic on which we say hello to subscribers while synchronizing.
+    pub fn sync_topic(topic: &str) -> String {
+        format!("{}-sync", topic)
+    }
+
+    // The topic on which we publish heartbeats while a phase runs.
+    pub fn heartbeat_topic(topic: &str) -> String {
+        format!("{}-heartbeat", topic)
+    }
+
+    fn next(&mut self, event: Event) {
+        self.state = fsm::next(&self.transitions, &self.state, event);
+    }
+
+    // This is where the actual work happens (downloads, external commands, ...), so it
+    // blocks, and should be called from a blocking context.
+    pub fn run(&mut self) {
         match &self.state {
             State::NotAvailable => {
                 // println!("Not Available");
                 // println!("Sending Download Event");
             }
-            State::DownloadingInProgress { started_at } => {
+            State::DownloadingInProgress { started_at, .. } => {
+                let phases = self.phases.clone();
+                let _permit = phases.acquire(Phase::Download, &self.work);
+                if self.work.is_cancelled() {
+                    self.events
+                        .push_back(Event::DownloadingError(error::Report::message(
+                            Phase::Download,
+                            error::Kind::Cancelled,
+                            "Download cancelled",
+                        )));
+                    return;
+                }
                 // println!(
                 //     "Downloading {} / {} / {}",
                 //     self.index_type, self.data_source, self.region
                 // );
                 match self.data_source.as_ref() {
                     "cosmogony" => {
-                        match osm::download_osm_region(self.working_dir.clone(), &self.region) {
+                        match osm::download_osm_region(
+                            self.working_dir.clone(),
+                            &self.region,
+                            &self.work,
+                            &self.progress,
+                        ) {
                             Ok(file_path) => {
                                 let duration = started_at.elapsed().unwrap();
                                 self.events
                                     .push_back(Event::DownloadingComplete(file_path, duration));
                             }
                             Err(err) => {
-                                self.events.push_back(Event::DownloadingError(format!(
-                                    "Could not download: {}",
-                                    err
-                                )));
+                                self.events
+                                    .push_back(Event::DownloadingError(error::Report::new(
+                                        Phase::Download,
+                                        "Could not download",
+                                        &err,
+                                    )));
                             }
                         }
                     }
                     "bano" => {
-                        match bano::download_bano_region(self.working_dir.clone(), &self.region) {
+                        match bano::download_bano_region(
+                            self.working_dir.clone(),
+                            &self.region,
+                            &self.work,
+                            &self.progress,
+                        ) {
                             Ok(file_path) => {
                                 let duration = started_at.elapsed().unwrap();
                                 self.events
                                     .push_back(Event::DownloadingComplete(file_path, duration));
                             }
                             Err(err) => {
-                                self.events.push_back(Event::DownloadingError(format!(
-                                    "Could not download: {}",
-                                    err
-                                )));
+                                self.events
+                                    .push_back(Event::DownloadingError(error::Report::new(
+                                        Phase::Download,
+                                        "Could not download",
+                                        &err,
+                                    )));
                             }
                         }
                     }
-                    "osm" => match osm::download_osm_region(self.working_dir.clone(), &self.region)
-                    {
+                    "osm" => match osm::download_osm_region(
+                        self.working_dir.clone(),
+                        &self.region,
+                        &self.work,
+                        &self.progress,
+                    ) {
                         Ok(file_path) => {
                             let duration = started_at.elapsed().unwrap();
                             self.events
                                 .push_back(Event::DownloadingComplete(file_path, duration));
                         }
                         Err(err) => {
-                            self.events.push_back(Event::DownloadingError(format!(
-                                "Could not download: {}",
-                                err
-                            )));
+                            self.events
+                                .push_back(Event::DownloadingError(error::Report::new(
+                                    Phase::Download,
+                                    "Could not download",
+                                    &err,
+                                )));
                         }
                     },
                     "ntfs" => {
-                        match ntfs::download_ntfs_region(self.working_dir.clone(), &self.region) {
+                        match ntfs::download_ntfs_region(
+                            self.working_dir.clone(),
+                            &self.region,
+                            &self.work,
+                            &self.progress,
+                        ) {
                             Ok(file_path) => {
                                 let duration = started_at.elapsed().unwrap();
                                 self.events
                                     .push_back(Event::DownloadingComplete(file_path, duration));
                             }
                             Err(err) => {
-                                self.events.push_back(Event::DownloadingError(format!(
-                                    "Could not download: {}",
-                                    err
-                                )));
+                                self.events
+                                    .push_back(Event::DownloadingError(error::Report::new(
+                                        Phase::Download,
+                                        "Could not download",
+                                        &err,
+                                    )));
                             }
                         }
                     }
                     _ => {
-                        self.events.push_back(Event::DownloadingError(format!(
-                            "Dont know how to download {}",
-                            &self.data_source
-                        )));
+                        self.events
+                            .push_back(Event::DownloadingError(error::Report::message(
+                                Phase::Download,
+                                error::Kind::Unsupported,
+                                format!("Dont know how to download {}", &self.data_source),
+                            )));
                     }
                 }
             }
-            State::DownloadingError { details: _ } => {
-                // We can't stay in downloading error state, we need to go back to not available
-                // to terminate the fsm
+            State::DownloadingError { error, attempts } => {
+                // Unless we try again, we can't stay in downloading error state, we need to go
+                // to failed to terminate the fsm
                 // It might be the place to do some cleanup
-                self.events.push_back(Event::Reset);
+                let event = self.recover(error, *attempts, Event::Fail);
+                self.events.push_back(event);
             }
             State::Downloaded {
                 file_path,
@@ -295,43 +670,66 @@ impl Driver {
                         self.events.push_back(Event::Process(file_path.clone()));
                     }
                     _ => {
-                        self.events.push_back(Event::Index(file_path.clone()));
+                        let event = self.check_cluster(file_path.clone());
+                        self.events.push_back(event);
                     }
                 }
             }
             State::ProcessingInProgress {
                 file_path,
                 started_at,
-            } => match self.data_source.as_ref() {
-                "cosmogony" => {
-                    match cosmogony::generate_cosmogony(
-                        self.cosmogony_dir.clone(),
-                        self.working_dir.clone(),
-                        file_path.clone(),
-                        &self.region,
-                    ) {
-                        Ok(path) => {
-                            let duration = started_at.elapsed().unwrap();
-                            self.events
-                                .push_back(Event::ProcessingComplete(path, duration));
+                ..
+            } => {
+                let phases = self.phases.clone();
+                let _permit = phases.acquire(Phase::Processing, &self.work);
+                if self.work.is_cancelled() {
+                    self.events
+                        .push_back(Event::ProcessingError(error::Report::message(
+                            Phase::Processing,
+                            error::Kind::Cancelled,
+                            "Processing cancelled",
+                        )));
+                    return;
+                }
+                match self.data_source.as_ref() {
+                    "cosmogony" => {
+                        match cosmogony::generate_cosmogony(
+                            self.cosmogony_dir.clone(),
+                            self.working_dir.clone(),
+                            file_path.clone(),
+                            &self.region,
+                            &self.work,
+                        ) {
+                            Ok(path) => {
+                                let duration = started_at.elapsed().unwrap();
+                                self.events
+                                    .push_back(Event::ProcessingComplete(path, duration));
+                            }
+                            Err(err) => {
+                                self.events
+                                    .push_back(Event::ProcessingError(error::Report::new(
+                                        Phase::Processing,
+                                        "Could not process",
+                                        &err,
+                                    )));
+                            }
                         }
-                        Err(err) => {
-                            self.events.push_back(Event::ProcessingError(format!(
-                                "Could not process: {}",
-                                err
+                    }
+                    _ => {
+                        self.events
+                            .push_back(Event::ProcessingError(error::Report::message(
+                                Phase::Processing,
+                                error::Kind::Unsupported,
+                                format!("Dont know how to process {}", &self.data_source),
                             )));
-                        }
                     }
                 }
-                _ => {
-                    self.events.push_back(Event::ProcessingError(format!(
-                        "Dont know how to process {}",
-                        &self.data_source
-                    )));
-                }
-            },
-            State::ProcessingError { details: _ } => {
-                self.events.push_back(Event::Reset);
+            }
+            State::ProcessingError {
+                error, attempts, ..
+            } => {
+                let event = self.recover(error, *attempts, Event::Fail);
+                self.events.push_back(event);
             }
             State::Processed {
                 file_path,
@@ -343,11 +741,27 @@ impl Driver {
                 //     self.region,
                 //     duration.as_secs()
                 // );
-                self.events.push_back(Event::Index(file_path.clone()));
+                let event = self.check_cluster(file_path.clone());
+                self.events.push_back(event);
+            }
+            State::WaitingForCluster { file_path, .. } => {
+                // The watchdog stops the wait on timeout, and so does a cancellation
+                if !self.work.sleep(CLUSTER_CHECK_DELAY) {
+                    self.events
+                        .push_back(Event::IndexingError(error::Report::message(
+                            Phase::Indexing,
+                            error::Kind::Cancelled,
+                            "Waiting for Elasticsearch cancelled",
+                        )));
+                    return;
+                }
+                let event = self.check_cluster(file_path.clone());
+                self.events.push_back(event);
             }
             State::IndexingInProgress {
                 file_path,
                 started_at,
+                attempt,
             } => {
                 // println!(
                 //     "Indexing {} / {} / {} using {}",
@@ -356,22 +770,82 @@ impl Driver {
                 //     self.region,
                 //     file_path.display()
                 // );
+                let phases = self.phases.clone();
+                let _permit = phases.acquire(Phase::Indexing, &self.work);
+                if self.work.is_cancelled() {
+                    self.events
+                        .push_back(Event::IndexingError(error::Report::message(
+                            Phase::Indexing,
+                            error::Kind::Cancelled,
+                            "Indexing cancelled",
+                        )));
+                    return;
+                }
+                // A previous attempt may have left a half built index behind
+                if let (true, Some(snapshot)) = (*attempt > 1, &self.snapshot) {
+                    let alias = elasticsearch::index_alias(&self.index_type, &self.dataset);
+                    if let Err(err) = elasticsearch::rollback(&self.es, &alias, snapshot) {
+                        self.events
+                            .push_back(Event::IndexingError(error::Report::new(
+                                Phase::Indexing,
+                                "Could not clean up previous attempt",
+                                &err,
+                            )));
+                        return;
+                    }
+                }
+                // Before the importer touches Elasticsearch, we take note of the indices and of
+                // the alias, so that we can restore them if anything goes wrong.
+                let alias = elasticsearch::index_alias(&self.index_type, &self.dataset);
+                // A resumed job already has the snapshot taken before it was interrupted.
+                let snapshot = match &self.snapshot {
+                    Some(snapshot) => Ok(snapshot.clone()),
+                    None => elasticsearch::snapshot(&self.es, &alias),
+                };
+                match snapshot {
+                    Ok(snapshot) => {
+                        self.snapshot = Some(snapshot);
+                        // We need it to rollback after a crash too.
+                        if let Err(err) = self.write_journal() {
+                            self.events
+                                .push_back(Event::IndexingError(error::Report::new(
+                                    Phase::Indexing,
+                                    "Could not journal Elasticsearch before indexing",
+                                    &err,
+                                )));
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        self.events
+                            .push_back(Event::IndexingError(error::Report::new(
+                                Phase::Indexing,
+                                "Could not inspect Elasticsearch before indexing",
+                                &err,
+                            )));
+                        return;
+                    }
+                }
                 match self.data_source.as_ref() {
                     "bano" => {
                         match bano::index_bano_region(
                             self.mimirs_dir.clone(),
-                            self.es.clone(),
+                            &self.es,
+                            &self.dataset,
                             file_path.clone(),
+                            &self.work,
                         ) {
                             Ok(()) => {
                                 let duration = started_at.elapsed().unwrap();
                                 self.events.push_back(Event::IndexingComplete(duration));
                             }
                             Err(err) => {
-                                self.events.push_back(Event::IndexingError(format!(
-                                    "Could not index BANO: {}",
-                                    err
-                                )));
+                                self.events
+                                    .push_back(Event::IndexingError(error::Report::new(
+                                        Phase::Indexing,
+                                        "Could not index BANO",
+                                        &err,
+                                    )));
                             }
                         }
                     }
@@ -385,117 +859,730 @@ impl Driver {
                             _ => None,
                         };
 
-                        if index.is_none() {
-                            self.events.push_back(Event::IndexingError(format!(
-                                "Could not index {} using OSM",
-                                self.index_type
-                            )));
-                        } else {
-                            let index = index.unwrap();
+                        if let Some(index) = index {
                             match osm::index_osm_region(
                                 self.mimirs_dir.clone(),
-                                self.es.clone(),
+                                &self.es,
+                                &self.dataset,
                                 file_path.clone(),
                                 index.0,
                                 index.1,
                                 index.2,
                                 8, // 8 = default city level
+                                &self.work,
                             ) {
                                 Ok(()) => {
                                     let duration = started_at.elapsed().unwrap();
                                     self.events.push_back(Event::IndexingComplete(duration));
                                 }
                                 Err(err) => {
-                                    self.events.push_back(Event::IndexingError(format!(
-                                        "Could not index OSM: {}",
-                                        err
-                                    )));
+                                    self.events.push_back(Event::IndexingError(
+                                        error::Report::new(
+                                            Phase::Indexing,
+                                            "Could not index OSM",
+                                            &err,
+                                        ),
+                                    ));
                                 }
                             }
+                        } else {
+                            self.events
+                                .push_back(Event::IndexingError(error::Report::message(
+                                    Phase::Indexing,
+                                    error::Kind::Unsupported,
+                                    format!("Could not index {} using OSM", self.index_type),
+                                )));
                         }
                     }
                     "cosmogony" => {
                         match cosmogony::index_cosmogony_region(
                             self.mimirs_dir.clone(),
-                            self.es.clone(),
+                            &self.es,
+                            &self.dataset,
                             file_path.clone(),
+                            &self.work,
                         ) {
                             Ok(()) => {
                                 let duration = started_at.elapsed().unwrap();
                                 self.events.push_back(Event::IndexingComplete(duration));
                             }
                             Err(err) => {
-                                self.events.push_back(Event::IndexingError(format!(
-                                    "Could not index cosmogony: {}",
-                                    err
-                                )));
+                                self.events
+                                    .push_back(Event::IndexingError(error::Report::new(
+                                        Phase::Indexing,
+                                        "Could not index cosmogony",
+                                        &err,
+                                    )));
                             }
                         }
                     }
                     "ntfs" => {
                         match ntfs::index_ntfs_region(
                             self.mimirs_dir.clone(),
-                            self.es.clone(),
+                            &self.es,
+                            &self.dataset,
                             file_path.clone(),
+                            &self.work,
                         ) {
                             Ok(()) => {
                                 let duration = started_at.elapsed().unwrap();
                                 self.events.push_back(Event::IndexingComplete(duration));
                             }
                             Err(err) => {
-                                self.events.push_back(Event::IndexingError(format!(
-                                    "Could not index NTFS: {}",
-                                    err
-                                )));
+                                self.events
+                                    .push_back(Event::IndexingError(error::Report::new(
+                                        Phase::Indexing,
+                                        "Could not index NTFS",
+                                        &err,
+                                    )));
                             }
                         }
                     }
                     _ => {
-                        self.events.push_back(Event::IndexingError(format!(
-                            "Dont know how to index {}",
-                            &self.data_source
-                        )));
+                        self.events
+                            .push_back(Event::IndexingError(error::Report::message(
+                                Phase::Indexing,
+                                error::Kind::Unsupported,
+                                format!("Dont know how to index {}", &self.data_source),
+                            )));
                     }
                 }
             }
-            State::IndexingError { details: _ } => {
-                self.events.push_back(Event::Reset);
+            State::IndexingError {
+                file_path,
+                error,
+                attempts,
+            } => {
+                // If we don't try again, the importer may have left a half built index behind
+                let event = match self.recover(error, *attempts, Event::Rollback) {
+                    // Elasticsearch may be why the attempt failed, so we check it again first
+                    Event::Retry => match self.check_cluster(file_path.clone()) {
+                        Event::Index(_) => Event::Retry,
+                        Event::ClusterUnavailable(file_path, details) => {
+                            Event::ClusterUnavailable(file_path, details)
+                        }
+                        _ => Event::Rollback,
+                    },
+                    event => event,
+                };
+                self.events.push_back(event);
                 // println!("Indexing Error: {}", details);
             }
             State::Indexed { duration: _ } => {
                 self.events.push_back(Event::Validate);
             }
-            State::ValidationInProgress => {
-                std::thread::sleep(std::time::Duration::from_secs(1));
-                self.events.push_back(Event::ValidationComplete);
+            State::ValidationInProgress { .. } => {
+                // We validate the index freshly created by the importer, and only if it holds
+                // documents do we publish it, by pointing the alias to it. Until then, the
+                // previous index is still the one served.
+                let alias = elasticsearch::index_alias(&self.index_type, &self.dataset);
+                match self.validate(&alias) {
+                    Ok(index) => {
+                        self.events
+                            .push_back(Event::ValidationComplete(index, alias));
+                    }
+                    Err(err) => {
+                        self.events
+                            .push_back(Event::ValidationError(error::Report::new(
+                                Phase::Validation,
+                                &format!("Could not validate {}", alias),
+                                &err,
+                            )));
+                    }
+                }
             }
-            State::ValidationError { details: _ } => {
-                self.events.push_back(Event::Reset);
+            State::ValidationError { error, attempts } => {
+                let event = self.recover(error, *attempts, Event::Rollback);
+                self.events.push_back(event);
             }
-            State::Available => {}
+            State::RollbackInProgress { .. } => {
+                let alias = elasticsearch::index_alias(&self.index_type, &self.dataset);
+                match &self.snapshot {
+                    Some(snapshot) => match elasticsearch::rollback(&self.es, &alias, snapshot) {
+                        Ok(indices) => {
+                            self.events.push_back(Event::RollbackComplete(indices));
+                        }
+                        Err(err) => {
+                            self.events.push_back(Event::RollbackError(format!(
+                                "Could not rollback {}: {}",
+                                alias, err
+                            )));
+                        }
+                    },
+                    None => {
+                        // We failed before indexing, so there is nothing to undo.
+                        self.events.push_back(Event::RollbackComplete(Vec::new()));
+                    }
+                }
+            }
+            State::RollbackError { .. } => {
+                self.events.push_back(Event::Fail);
+            }
+            State::RolledBack { .. } => {
+                self.events.push_back(Event::Fail);
+            }
+            State::Available { .. } => {}
+            State::Failed { .. } => {}
+            State::Paused { .. } => {}
+            State::Cancelled => {}
             State::Failure(_) => {}
         }
     }
 
+    // Runs the work of the current state. While a phase runs, a watchdog publishes heartbeats,
+    // and stops the phase if it runs out of time.
+    async fn run_watched(&mut self) {
+        let phase = match self.state.phase() {
+            Some(phase) => phase,
+            None => {
+                self.phase_started = None;
+                tokio::task::block_in_place(|| self.run());
+                return;
+            }
+        };
+        // A phase may span several states, eg waiting for the cluster then indexing, and its
+        // timeout covers them all.
+        let started = match self.phase_started {
+            Some((current, started)) if current == phase => started,
+            _ => Instant::now(),
+        };
+        self.phase_started = Some((phase, started));
+        self.work = self.cancellation.child();
+        self.progress = control::Progress::new();
+        let timeout = self.timeouts.get(&self.data_source, phase);
+        let watchdog = Watchdog {
+            publish: self.publish.clone(),
+            topic: Driver::heartbeat_topic(&self.topic),
+            job_id: self.job_id.clone(),
+            sequence: self.sequence.saturating_sub(1),
+            phase,
+            started,
+            timeout,
+            work: self.work.clone(),
+            progress: self.progress.clone(),
+            timed_out: Arc::new(AtomicBool::new(false)),
+        };
+        let timed_out = watchdog.timed_out.clone();
+        let (stop, stop_rx) = oneshot::channel();
+        let watchdog = tokio::spawn(watchdog.watch(stop_rx));
+        tokio::task::block_in_place(|| self.run());
+        let _ = stop.send(());
+        let _ = watchdog.await;
+        // The work we stopped reports an error, which we replace with the timeout. Unless the
+        // job was cancelled meanwhile, or the work was done anyway.
+        if let (true, false, Some(timeout)) = (
+            timed_out.load(Ordering::SeqCst),
+            self.cancellation.is_cancelled(),
+            timeout,
+        ) {
+            let failed = matches!(
+                self.events.back(),
+                Some(Event::DownloadingError(_))
+                    | Some(Event::ProcessingError(_))
+                    | Some(Event::IndexingError(_))
+                    | Some(Event::ValidationError(_))
+            );
+            if failed {
+                self.events.pop_back();
+                self.events.push_back(Event::Timeout(timeout));
+            }
+        }
+    }
+
+    // After a phase failed, we try it again if the policy lets us, and if trying again may help,
+    // otherwise we give up.
+    fn recover(&self, error: &error::Report, attempts: u32, give_up: Event) -> Event {
+        if self.cancellation.is_cancelled()
+            || !error.retryable
+            || !self.recovery.should_retry(error.phase, attempts)
+        {
+            return give_up;
+        }
+        // A job cancelled while we wait has no use for another attempt
+        if !self
+            .cancellation
+            .sleep(self.recovery.get(error.phase).delay)
+        {
+            return give_up;
+        }
+        Event::Retry
+    }
+
+    // Before indexing, we make sure Elasticsearch is in a state to receive the data. If it's not,
+    // we wait for it, and the FSM decides when we waited too long.
+    fn check_cluster(&self, file_path: PathBuf) -> Event {
+        match elasticsearch::check_health(&self.es) {
+            elasticsearch::Health::Ready => Event::Index(file_path),
+            elasticsearch::Health::Unsupported(details) => Event::IndexingError(
+                error::Report::message(Phase::Indexing, error::Kind::Unsupported, details),
+            ),
+            elasticsearch::Health::Unavailable(details) => {
+                Event::ClusterUnavailable(file_path, details)
+            }
+        }
+    }
+
+    fn validate(&self, alias: &str) -> Result<String, error::Error> {
+        let index = elasticsearch::find_latest_index(&self.es, alias)?;
+        let count = elasticsearch::count_documents(&self.es, &index)?;
+        if count == 0 {
+            return Err(error::Error::MiscError {
+                details: format!("Index {} is empty", index),
+            });
+        }
+        let previous = elasticsearch::aliased_indices(&self.es, alias)?;
+        elasticsearch::swap_alias(&self.es, alias, &index, &previous)?;
+        Ok(index)
+    }
+
+    fn journal(&self) -> journal::Journal {
+        journal::Journal::new(&self.journal_dir, &self.job_id)
+    }
+
+    // Records the last state published, or forgets the job if it is over.
+    fn write_journal(&self) -> Result<(), error::Error> {
+        let journal = self.journal();
+        if !journal::is_resumable(&self.state) {
+            return journal.remove();
+        }
+        let envelope = self
+            .status
+            .lock()
+            .ok()
+            .and_then(|status| status.current.clone());
+        match envelope {
+            Some(envelope) => journal.append(&journal::Entry {
+                envelope,
+                snapshot: self.snapshot.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // If the job was interrupted, we restore its last state from the journal. The work of a phase
+    // in progress is lost, so that phase starts over. A job another driver is on is not ours.
+    fn resume(&mut self) -> Result<bool, error::Error> {
+        self.lock = self.journal().lock()?;
+        if self.lock.is_none() {
+            return Err(error::Error::MiscError {
+                details: format!("Job {} is already driven by another driver", self.job_id),
+            });
+        }
+        let entry = match self.journal().last()? {
+            Some(entry) if journal::is_resumable(&entry.envelope.state) => entry,
+            _ => return Ok(false),
+        };
+        self.sequence = entry.envelope.sequence + 1;
+        self.snapshot = entry.snapshot;
+        self.state = self.restart(entry.envelope.state);
+        Ok(true)
+    }
+
+    fn restart(&self, state: State) -> State {
+        match state {
+            State::DownloadingInProgress { attempt, .. } => State::DownloadingInProgress {
+                started_at: SystemTime::now(),
+                attempt,
+            },
+            State::ProcessingInProgress {
+                file_path, attempt, ..
+            } => State::ProcessingInProgress {
+                file_path,
+                started_at: SystemTime::now(),
+                attempt,
+            },
+            State::IndexingInProgress {
+                file_path, attempt, ..
+            } => {
+                // The importer may have left a half built index behind
+                if let Some(snapshot) = &self.snapshot {
+                    let alias = elasticsearch::index_alias(&self.index_type, &self.dataset);
+                    if let Err(err) = elasticsearch::rollback(&self.es, &alias, snapshot) {
+                        return State::IndexingError {
+                            file_path,
+                            error: error::Report::new(
+                                Phase::Indexing,
+                                "Could not clean up interrupted indexing",
+                                &err,
+                            ),
+                            attempts: attempt,
+                        };
+                    }
+                }
+                State::IndexingInProgress {
+                    file_path,
+                    started_at: SystemTime::now(),
+                    attempt,
+                }
+            }
+            State::Paused { state } => State::Paused {
+                state: Box::new(self.restart(*state)),
+            },
+            state => state,
+        }
+    }
+
+    // What the job went through so far: durations, errors, ...
+    pub fn summary(&self) -> Option<history::Record> {
+        self.status
+            .lock()
+            .ok()
+            .and_then(|status| history::Record::from_status(&status))
+    }
+
+    // Losing the record of a job is no reason to fail it, so we only complain.
+    fn record_history(&self) {
+        if let Some(record) = self.summary() {
+            if let Err(err) = history::History::new(self.history.clone()).append(&record) {
+                eprintln!(
+                    "Could not record job {}: {}",
+                    self.job_id,
+                    self.redactor.redact(&format!("{}", err))
+                );
+            }
+        }
+    }
+
+    // Undo what was done in Elasticsearch, if we got that far.
+    fn cancel(&mut self) {
+        if let Some(snapshot) = &self.snapshot {
+            let alias = elasticsearch::index_alias(&self.index_type, &self.dataset);
+            if let Err(err) = elasticsearch::rollback(&self.es, &alias, snapshot) {
+                eprintln!(
+                    "Could not rollback {} after cancellation: {}",
+                    alias,
+                    self.redactor.redact(&format!("{}", err))
+                );
+            }
+        }
+    }
+
+    async fn send(&mut self, topic: &str, payload: &str) -> Result<(), error::Error> {
+        self.publish.notify(topic, payload).await
+    }
+
+    async fn publish_state(&mut self) -> Result<(), error::Error> {
+        let topic = self.topic.clone();
+        let envelope = Envelope {
+            job_id: self.job_id.clone(),
+            sequence: self.sequence,
+            timestamp: SystemTime::now(),
+            data_source: self.data_source.clone(),
+            region: self.region.clone(),
+            index_type: self.index_type.clone(),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            state: self.state.redact(&self.redactor),
+        };
+        let payload = serde_json::to_string(&envelope).context(error::SerdeJSONError {
+            details: String::from("Could not serialize state"),
+        })?;
+        self.trigger_webhooks(&envelope);
+        if let Ok(mut status) = self.status.lock() {
+            status.record(envelope);
+        }
+        self.write_journal()?;
+        self.sequence += 1;
+        self.send(&topic, &payload).await
+    }
+
+    // Posts the state to the webhooks it triggers, on their own threads, so that a slow receiver
+    // doesn't hold up the job.
+    fn trigger_webhooks(&mut self, envelope: &Envelope) {
+        for webhook in self.webhooks.iter() {
+            if !webhook.triggers(&envelope.state) {
+                continue;
+            }
+            let body = match webhook.body(envelope) {
+                Ok(body) => body,
+                Err(err) => {
+                    eprintln!("Could not notify {}: {}", webhook.name(), err);
+                    continue;
+                }
+            };
+            let webhook = webhook.clone();
+            let job_id = self.job_id.clone();
+            let redactor = self.redactor.clone();
+            self.deliveries.push(tokio::task::spawn_blocking(move || {
+                if let Err(err) = webhook.deliver(&body) {
+                    eprintln!(
+                        "Could not notify {} of job {}: {}",
+                        webhook.name(),
+                        job_id,
+                        redactor.redact(&format!("{}", err))
+                    );
+                }
+            }));
+        }
+    }
+
+    // A subscriber only receives messages once its subscription reached us, which takes some
+    // time after it connected. So we keep saying hello on the sync topic, and a subscriber
+    // which received a hello is subscribed, and tells us so on the endpoint.
+    async fn synchronize(&mut self) -> Result<(), error::Error> {
+        let topic = Driver::sync_topic(&self.topic);
+        let mut synchronized = 0;
+        while synchronized < self.subscribers {
+            self.send(&topic, "hello").await?;
+            match tokio::time::timeout(SYNC_INTERVAL, self.synced.recv()).await {
+                Ok(Some(())) => synchronized += 1,
+                Ok(None) => {
+                    return Err(error::Error::MiscError {
+                        details: String::from("Endpoint stopped before subscribers synchronized"),
+                    });
+                }
+                Err(_) => {} // No one yet, say hello again
+            }
+        }
+        Ok(())
+    }
+
+    // Answers requests while we drive, until shutdown.
+    #[cfg(feature = "zmq")]
+    fn serve_endpoint(
+        &mut self,
+        shutdown: oneshot::Receiver<()>,
+    ) -> Option<tokio::task::JoinHandle<Result<(), error::Error>>> {
+        self.endpoint
+            .take()
+            .map(|endpoint| tokio::spawn(endpoint.serve(shutdown)))
+    }
+
+    #[cfg(not(feature = "zmq"))]
+    fn serve_endpoint(
+        &mut self,
+        _shutdown: oneshot::Receiver<()>,
+    ) -> Option<tokio::task::JoinHandle<Result<(), error::Error>>> {
+        None
+    }
+
     pub async fn drive(&mut self) -> Result<(), error::Error> {
-        self.events.push_back(Event::Download);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let endpoint = self.serve_endpoint(shutdown_rx);
+        let resumed = tokio::task::block_in_place(|| self.resume())?;
+        self.synchronize().await?;
+        // Subscribers see where we start from
+        self.publish_state().await?;
+        if !resumed {
+            self.events.push_back(Event::Download);
+        } else if let State::Paused { .. } = self.state {
+            if let Some(command) = self.commands.recv().await {
+                self.events.push_back(Event::from(command));
+            }
+        } else {
+            // The work of the state we resume from still has to be done
+            self.run_watched().await;
+        }
         while let Some(event) = self.events.pop_front() {
-            self.next(event).await;
-            let i = self.topic.clone();
-            let j = serde_json::to_string(&self.state).unwrap();
-            let msg = vec![&i, &j];
-            let msg: Vec<Message> = msg.into_iter().map(Message::from).collect();
-            let res: MultipartIter<_, _> = msg.into();
-            self.publish.send(res).await.unwrap();
-            if let State::Failure(string) = &self.state {
-                println!("{}", string);
-                break;
-            } else {
-                self.run().await;
+            // Commands from clients take precedence over the events of the FSM. The state the
+            // event was meant for has already run, so we must not run it again.
+            let (event, command) = match self.commands.try_recv() {
+                Ok(command) => {
+                    self.events.push_front(event);
+                    (Event::from(command), true)
+                }
+                Err(_) => (event, false),
+            };
+            let previous = self.state.clone();
+            self.next(event);
+            match &self.state {
+                State::Failure(string) => {
+                    eprintln!("{}", self.redactor.redact(string));
+                    self.publish_state().await?;
+                    break;
+                }
+                State::Cancelled => {
+                    self.events.clear();
+                    tokio::task::block_in_place(|| self.cancel());
+                    self.publish_state().await?;
+                    break;
+                }
+                State::Paused { .. } => {
+                    if previous != self.state {
+                        self.publish_state().await?;
+                    }
+                    // We wait for a client to tell us what to do.
+                    match self.commands.recv().await {
+                        Some(command) => self.events.push_front(Event::from(command)),
+                        None => break,
+                    }
+                }
+                _ => {
+                    if previous != self.state || !command {
+                        self.publish_state().await?;
+                    }
+                    if !command {
+                        self.run_watched().await;
+                    }
+                }
             }
         }
-        self.publish.close().await.context(error::ZMQSendError {
-            details: format!("Could not close publishing endpoint"),
-        })
+        // Unless it was left paused, the job is over. Either way, someone else may take it now.
+        if !journal::is_resumable(&self.state) {
+            self.record_history();
+        }
+        self.lock = None;
+        // The last state is usually the one webhooks are waiting for.
+        for delivery in self.deliveries.drain(..) {
+            let _ = delivery.await;
+        }
+        let _ = shutdown.send(());
+        if let Some(endpoint) = endpoint {
+            endpoint.await.context(error::TokioJoinError {
+                details: String::from("Could not run endpoint to completion"),
+            })??;
+        }
+        // The publication socket is closed when the last driver using it is dropped.
+        Ok(())
+    }
+}
+
+// Watches a phase in progress, on its own task, since the driver is busy with the work.
+struct Watchdog {
+    publish: Publisher,
+    topic: String,
+    job_id: String,
+    sequence: u64,
+    phase: Phase,
+    started: Instant, // When the phase started, maybe in a previous state
+    timeout: Option<Duration>,
+    work: control::Cancellation, // What we cancel when the phase runs out of time
+    progress: control::Progress,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    async fn watch(self, mut stop: oneshot::Receiver<()>) {
+        let started = self.started;
+        let mut beat = Instant::now();
+        loop {
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = tokio::time::delay_for(WATCHDOG_INTERVAL) => {}
+            }
+            let elapsed = started.elapsed();
+            if let Some(timeout) = self.timeout {
+                if elapsed >= timeout && !self.timed_out.swap(true, Ordering::SeqCst) {
+                    self.work.cancel();
+                }
+            }
+            if beat.elapsed() >= HEARTBEAT_INTERVAL {
+                beat = Instant::now();
+                // A missed heartbeat is no reason to stop the work
+                if let Err(err) = self.heartbeat(elapsed).await {
+                    eprintln!(
+                        "Could not publish heartbeat for job {}: {}",
+                        self.job_id, err
+                    );
+                }
+            }
+        }
+    }
+
+    async fn heartbeat(&self, elapsed: Duration) -> Result<(), error::Error> {
+        let heartbeat = Heartbeat {
+            job_id: self.job_id.clone(),
+            sequence: self.sequence,
+            timestamp: SystemTime::now(),
+            phase: self.phase,
+            elapsed,
+            timeout: self.timeout,
+            progress: self.progress.amount(),
+        };
+        let payload = serde_json::to_string(&heartbeat).context(error::SerdeJSONError {
+            details: String::from("Could not serialize heartbeat"),
+        })?;
+        self.publish.notify(&self.topic, &payload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn publishes_states_until_failed() {
+        let dir = std::env::temp_dir().join(format!("driver-{}", std::process::id()));
+        let memory = Arc::new(notify::Memory::new());
+        let es = elasticsearch::Connection::new(url::Url::parse("http://localhost:9200").unwrap());
+        let (mut driver, _handle) = Driver::with_publisher(
+            "admins",
+            "unknown",
+            "region",
+            es,
+            String::from("state"),
+            memory.clone(),
+        );
+        driver.set_working_dir(&dir);
+        driver.drive().await.unwrap();
+
+        let messages = memory.messages();
+        assert!(messages.iter().all(|(topic, _)| topic == "state"));
+        let envelopes: Vec<Envelope> = messages
+            .iter()
+            .map(|(_, payload)| serde_json::from_str(payload).unwrap())
+            .collect();
+        let sequences: Vec<u64> = envelopes.iter().map(|envelope| envelope.sequence).collect();
+        assert_eq!(sequences, vec![0, 1, 2, 3]);
+        assert!(envelopes
+            .iter()
+            .all(|envelope| envelope.job_id == driver.job_id()));
+        assert_eq!(envelopes[0].state, State::NotAvailable);
+        assert_eq!(
+            envelopes[1].state.kind(),
+            fsm::StateKind::DownloadingInProgress
+        );
+        assert_eq!(envelopes[2].state.kind(), fsm::StateKind::DownloadingError);
+        match &envelopes[3].state {
+            State::Failed { error, attempts } => {
+                assert_eq!(error.kind, error::Kind::Unsupported);
+                assert_eq!(*attempts, 1);
+            }
+            state => panic!("Expected Failed, got {:?}", state),
+        }
+        // The job is over, so its journal is gone, and it is in the history
+        assert!(!dir
+            .join("journal")
+            .join(format!("{}.jsonl", driver.job_id()))
+            .exists());
+        assert!(dir.join("history.jsonl").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn waiting_for_cluster_is_indexing() {
+        let waiting = State::WaitingForCluster {
+            file_path: PathBuf::from("fr.osm.pbf"),
+            details: String::from("red"),
+            attempts: 1,
+            attempt: 1,
+        };
+        assert_eq!(waiting.phase(), Some(Phase::Indexing));
+        assert_eq!(State::NotAvailable.phase(), None);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn watchdog_times_out_the_whole_phase() {
+        // The phase started in a previous state, eg waiting for the cluster
+        let watchdog = Watchdog {
+            publish: Arc::new(notify::Memory::new()),
+            topic: String::from("heartbeat"),
+            job_id: String::from("job"),
+            sequence: 0,
+            phase: Phase::Indexing,
+            started: Instant::now() - Duration::from_secs(60),
+            timeout: Some(Duration::from_secs(30)),
+            work: control::Cancellation::new(),
+            progress: control::Progress::new(),
+            timed_out: Arc::new(AtomicBool::new(false)),
+        };
+        let work = watchdog.work.clone();
+        let timed_out = watchdog.timed_out.clone();
+        let (stop, stop_rx) = oneshot::channel();
+        let watch = tokio::spawn(watchdog.watch(stop_rx));
+        tokio::time::delay_for(WATCHDOG_INTERVAL * 2).await;
+        let _ = stop.send(());
+        watch.await.unwrap();
+        assert!(timed_out.load(Ordering::SeqCst));
+        assert!(work.is_cancelled());
     }
 }
@@ -0,0 +1,231 @@
+#[cfg(feature = "zmq")]
+use async_zmq::Message;
+#[cfg(feature = "zmq")]
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "zmq")]
+use snafu::ResultExt;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+#[cfg(feature = "zmq")]
+use tokio::sync::oneshot;
+
+use super::control;
+use super::driver::{Envelope, State};
+#[cfg(feature = "zmq")]
+use super::error;
+
+// The reply endpoint runs alongside the driver's publication socket. Subscribers use it to
+// synchronize before the driver starts, anyone can ask it where the driver is at, and control
+// it. Only the socket needs ZeroMQ, a driver keeps its status and handle without it.
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Request {
+    Sync,   // A subscriber tells the driver it is ready
+    Status, // Current state and history
+    Cancel,
+    Pause,
+    Resume,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Response {
+    Ready,
+    Status(Box<Status>),
+    Accepted, // The command was passed on to the driver
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Transition {
+    pub sequence: u64,
+    pub timestamp: SystemTime,
+    pub state: State,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Status {
+    pub current: Option<Envelope>, // None until the driver published its first state
+    pub history: Vec<Transition>,
+}
+
+impl Status {
+    pub fn record(&mut self, envelope: Envelope) {
+        self.history.push(Transition {
+            sequence: envelope.sequence,
+            timestamp: envelope.timestamp,
+            state: envelope.state.clone(),
+        });
+        self.current = Some(envelope);
+    }
+}
+
+// What a driver shares with whoever answers requests about it: its status, and the means to
+// control it.
+#[derive(Debug, Clone)]
+pub struct Handle {
+    pub status: Arc<Mutex<Status>>, // Maintained by the driver
+    pub commands: mpsc::UnboundedSender<control::Command>,
+    pub cancellation: control::Cancellation, // Stops the work in progress
+}
+
+impl Handle {
+    pub fn status(&self) -> Option<Status> {
+        self.status.lock().ok().map(|status| status.clone())
+    }
+
+    pub fn command(&self, command: control::Command) -> Response {
+        if command == control::Command::Cancel {
+            // We don't wait for the driver to stop what it's doing.
+            self.cancellation.cancel();
+        }
+        match self.commands.send(command) {
+            Ok(()) => Response::Accepted,
+            Err(_) => Response::Error(String::from("The driver is not running")),
+        }
+    }
+}
+
+#[cfg(feature = "zmq")]
+pub type Socket = async_zmq::reply::Reply<std::vec::IntoIter<Message>, Message>;
+
+#[cfg(feature = "zmq")]
+pub fn bind(endpoint: &str) -> Result<Socket, error::Error> {
+    async_zmq::reply(endpoint)
+        .context(error::ZMQSocketError {
+            details: format!("Could not reply on endpoint '{}'", endpoint),
+        })?
+        .bind()
+        .context(error::ZMQError {
+            details: String::from("Could not bind socket for replies"),
+        })
+}
+
+#[cfg(feature = "zmq")]
+// Receives a JSON request. If the request can't be understood, the error is meant for the
+// client.
+pub async fn recv_request<R: DeserializeOwned>(
+    socket: &Socket,
+) -> Result<Result<R, String>, error::Error> {
+    let msg = socket.recv().await.context(error::ZMQRequestReplyError {
+        details: String::from("Could not receive request"),
+    })?;
+    let request = match msg.iter().next().and_then(|msg| msg.as_str()) {
+        Some(request) => {
+            serde_json::from_str(request).map_err(|err| format!("Invalid request: {}", err))
+        }
+        None => Err(String::from("Empty request")),
+    };
+    Ok(request)
+}
+
+#[cfg(feature = "zmq")]
+pub async fn send_response<R: Serialize>(
+    socket: &Socket,
+    response: &R,
+) -> Result<(), error::Error> {
+    let response = serde_json::to_string(response).context(error::SerdeJSONError {
+        details: String::from("Could not serialize response"),
+    })?;
+    socket
+        .send(vec![Message::from(response.as_str())])
+        .await
+        .context(error::ZMQRequestReplyError {
+            details: String::from("Could not send response"),
+        })
+}
+
+#[cfg(feature = "zmq")]
+pub struct Endpoint {
+    socket: Socket,
+    handle: Handle,
+    synced: mpsc::UnboundedSender<()>, // Tells the driver a subscriber is ready
+}
+
+#[cfg(feature = "zmq")]
+impl Endpoint {
+    pub fn new(
+        endpoint: &str,
+        handle: Handle,
+        synced: mpsc::UnboundedSender<()>,
+    ) -> Result<Self, error::Error> {
+        Ok(Endpoint {
+            socket: bind(endpoint)?,
+            handle,
+            synced,
+        })
+    }
+
+    fn respond(&self, request: Request) -> Response {
+        match request {
+            Request::Sync => {
+                // The driver may be long gone, the subscriber doesn't need to wait for it.
+                let _ = self.synced.send(());
+                Response::Ready
+            }
+            Request::Status => match self.handle.status() {
+                Some(status) => Response::Status(Box::new(status)),
+                None => Response::Error(String::from("Status unavailable")),
+            },
+            Request::Cancel => self.handle.command(control::Command::Cancel),
+            Request::Pause => self.handle.command(control::Command::Pause),
+            Request::Resume => self.handle.command(control::Command::Resume),
+        }
+    }
+
+    // Answers requests until told to shutdown.
+    pub async fn serve(self, shutdown: oneshot::Receiver<()>) -> Result<(), error::Error> {
+        let mut shutdown = shutdown;
+        loop {
+            let request = tokio::select! {
+                request = recv_request(&self.socket) => request?,
+                _ = &mut shutdown => break,
+            };
+            let response = match request {
+                Ok(request) => self.respond(request),
+                Err(details) => Response::Error(details),
+            };
+            send_response(&self.socket, &response).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zmq")]
+// Sends a single request to the endpoint, and waits for the response.
+pub async fn request<Q: Serialize, R: DeserializeOwned>(
+    endpoint: &str,
+    request: &Q,
+) -> Result<R, error::Error> {
+    let socket = async_zmq::request(endpoint)
+        .context(error::ZMQSocketError {
+            details: format!("Could not request on endpoint '{}'", endpoint),
+        })?
+        .connect()
+        .context(error::ZMQError {
+            details: String::from("Could not connect request"),
+        })?;
+    let request = serde_json::to_string(request).context(error::SerdeJSONError {
+        details: String::from("Could not serialize request"),
+    })?;
+    socket
+        .send(vec![Message::from(request.as_str())])
+        .await
+        .context(error::ZMQRequestReplyError {
+            details: String::from("Could not send request"),
+        })?;
+    let msg = socket.recv().await.context(error::ZMQRequestReplyError {
+        details: String::from("Could not receive response"),
+    })?;
+    let response =
+        msg.iter()
+            .next()
+            .and_then(|msg| msg.as_str())
+            .ok_or(error::Error::MiscError {
+                details: String::from("Empty response"),
+            })?;
+    serde_json::from_str(response).context(error::SerdeJSONError {
+        details: String::from("Could not deserialize response"),
+    })
+}
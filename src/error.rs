@@ -1,5 +1,11 @@
+use serde::{Deserialize, Serialize};
 use snafu::Snafu;
 
+use super::driver::Phase;
+use super::redact;
+
+// The variants are named after what failed, eg IOError, rather than after how.
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("IOError {}: {}", details, source))]
@@ -18,6 +24,22 @@ pub enum Error {
     #[snafu(visibility(pub))]
     MiscError { details: String },
 
+    // The details tell how the process exited. Its stderr is kept apart, in reports.
+    #[snafu(display("Subprocess Error {}", details))]
+    #[snafu(visibility(pub))]
+    SubprocessError {
+        details: String,
+        code: Option<i32>, // None if the process was killed by a signal
+        stderr: String,    // The last lines the process printed on stderr
+    },
+
+    #[snafu(display("HTTP Server Error {}: {}", details, source))]
+    #[snafu(visibility(pub))]
+    HyperError {
+        details: String,
+        source: hyper::Error,
+    },
+
     #[snafu(display("URL Error {}: {}", details, source))]
     #[snafu(visibility(pub))]
     URLError {
@@ -39,6 +61,7 @@ pub enum Error {
         source: tokio::task::JoinError,
     },
 
+    #[cfg(feature = "zmq")]
     #[snafu(display("ZeroMQ Error {}: {}", details, source))]
     #[snafu(visibility(pub))]
     ZMQError {
@@ -46,6 +69,7 @@ pub enum Error {
         source: async_zmq::Error,
     },
 
+    #[cfg(feature = "zmq")]
     #[snafu(display("ZeroMQ Subscribe Error {}: {}", details, source))]
     #[snafu(visibility(pub))]
     ZMQSubscribeError {
@@ -53,6 +77,7 @@ pub enum Error {
         source: async_zmq::SubscribeError,
     },
 
+    #[cfg(feature = "zmq")]
     #[snafu(display("ZeroMQ Socket Error {}: {}", details, source))]
     #[snafu(visibility(pub))]
     ZMQSocketError {
@@ -60,6 +85,7 @@ pub enum Error {
         source: async_zmq::SocketError,
     },
 
+    #[cfg(feature = "zmq")]
     #[snafu(display("ZeroMQ Receive Error {}: {}", details, source))]
     #[snafu(visibility(pub))]
     ZMQRecvError {
@@ -67,6 +93,15 @@ pub enum Error {
         source: async_zmq::RecvError,
     },
 
+    #[cfg(feature = "zmq")]
+    #[snafu(display("ZeroMQ Request/Reply Error {}: {}", details, source))]
+    #[snafu(visibility(pub))]
+    ZMQRequestReplyError {
+        details: String,
+        source: async_zmq::RequestReplyError,
+    },
+
+    #[cfg(feature = "zmq")]
     #[snafu(display("ZeroMQ Send Error {}: {}", details, source))]
     #[snafu(visibility(pub))]
     ZMQSendError {
@@ -74,3 +109,179 @@ pub enum Error {
         source: async_zmq::SendError,
     },
 }
+
+// What kind of error stopped a phase, so that subscribers don't have to parse the details.
+// Subscribers match on these names, so they stay as they are.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Kind {
+    IO,
+    HTTP,
+    URL,
+    Serialization,
+    Subprocess,
+    ZMQ,
+    Task,
+    Elasticsearch, // The cluster is not in a state to index
+    Unsupported,   // We don't know how to do that, eg index an unknown data source
+    Timeout,
+    Cancelled,
+    Misc,
+}
+
+impl Kind {
+    // Whether trying again may help. It takes a change of configuration to fix the others.
+    pub fn is_retryable(self) -> bool {
+        !matches!(
+            self,
+            Kind::URL | Kind::Serialization | Kind::Unsupported | Kind::Cancelled
+        )
+    }
+}
+
+// An error as error states carry it: serializable, unlike Error, and with what a subscriber needs
+// to decide what to do about it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Report {
+    pub kind: Kind,
+    pub phase: Phase,
+    pub details: String, // What we were doing, and what went wrong
+    pub retryable: bool,
+    pub exit_code: Option<i32>, // For a subprocess which exited with an error
+    pub stderr: Option<String>, // The last lines a subprocess printed on stderr
+    pub sources: Vec<String>,   // What caused the error, outermost first
+}
+
+impl Report {
+    pub fn new(phase: Phase, context: &str, err: &Error) -> Self {
+        let kind = match err {
+            Error::IOError { source, .. } if source.kind() == std::io::ErrorKind::Interrupted => {
+                Kind::Cancelled
+            }
+            Error::IOError { .. } => Kind::IO,
+            Error::ReqwestError { .. } | Error::HyperError { .. } => Kind::HTTP,
+            Error::MiscError { .. } => Kind::Misc,
+            Error::SubprocessError { .. } => Kind::Subprocess,
+            Error::URLError { .. } => Kind::URL,
+            Error::SerdeJSONError { .. } => Kind::Serialization,
+            Error::TokioJoinError { .. } => Kind::Task,
+            #[cfg(feature = "zmq")]
+            Error::ZMQError { .. }
+            | Error::ZMQSubscribeError { .. }
+            | Error::ZMQSocketError { .. }
+            | Error::ZMQRecvError { .. }
+            | Error::ZMQRequestReplyError { .. }
+            | Error::ZMQSendError { .. } => Kind::ZMQ,
+        };
+        let (exit_code, stderr) = match err {
+            Error::SubprocessError { code, stderr, .. } => (*code, Some(stderr.clone())),
+            _ => (None, None),
+        };
+        let mut sources = Vec::new();
+        let mut source = std::error::Error::source(err);
+        while let Some(err) = source {
+            sources.push(format!("{}", err));
+            source = err.source();
+        }
+        Report {
+            kind,
+            phase,
+            details: format!("{}: {}", context, err),
+            retryable: kind.is_retryable(),
+            exit_code,
+            stderr,
+            sources,
+        }
+    }
+
+    // For an error which doesn't come from an Error.
+    pub fn message<S: Into<String>>(phase: Phase, kind: Kind, details: S) -> Self {
+        Report {
+            kind,
+            phase,
+            details: details.into(),
+            retryable: kind.is_retryable(),
+            exit_code: None,
+            stderr: None,
+            sources: Vec::new(),
+        }
+    }
+
+    pub fn redact(&self, redactor: &redact::Redactor) -> Self {
+        Report {
+            details: redactor.redact(&self.details),
+            stderr: self.stderr.as_ref().map(|stderr| redactor.redact(stderr)),
+            sources: self
+                .sources
+                .iter()
+                .map(|source| redactor.redact(source))
+                .collect(),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_subprocess_stderr_once() {
+        let err = Error::SubprocessError {
+            details: String::from("osm2mimir exited with code 2"),
+            code: Some(2),
+            stderr: String::from("thread 'main' panicked"),
+        };
+        let report = Report::new(Phase::Indexing, "Could not index", &err);
+        assert_eq!(report.kind, Kind::Subprocess);
+        assert!(report.retryable);
+        assert_eq!(report.exit_code, Some(2));
+        assert_eq!(report.stderr.as_deref(), Some("thread 'main' panicked"));
+        assert_eq!(
+            report.details,
+            "Could not index: Subprocess Error osm2mimir exited with code 2"
+        );
+    }
+
+    #[test]
+    fn maps_errors_to_kinds() {
+        let cancelled = Error::IOError {
+            details: String::from("Could not run"),
+            source: std::io::Error::new(std::io::ErrorKind::Interrupted, "Command cancelled"),
+        };
+        let report = Report::new(Phase::Download, "Could not download", &cancelled);
+        assert_eq!(report.kind, Kind::Cancelled);
+        assert!(!report.retryable);
+        assert_eq!(report.sources, vec![String::from("Command cancelled")]);
+
+        let io = Error::IOError {
+            details: String::from("Could not write"),
+            source: std::io::Error::other("disk full"),
+        };
+        let report = Report::new(Phase::Download, "Could not download", &io);
+        assert_eq!(report.kind, Kind::IO);
+        assert!(report.retryable);
+        assert_eq!(report.exit_code, None);
+        assert_eq!(report.stderr, None);
+
+        let url = url::Url::parse("not a url").unwrap_err();
+        let url = Error::URLError {
+            details: String::from("Could not parse"),
+            source: url,
+        };
+        let report = Report::new(Phase::Download, "Could not download", &url);
+        assert_eq!(report.kind, Kind::URL);
+        assert!(!report.retryable);
+
+        let misc = Error::MiscError {
+            details: String::from("oops"),
+        };
+        let report = Report::new(Phase::Validation, "Could not validate", &misc);
+        assert_eq!(report.kind, Kind::Misc);
+        assert!(report.retryable);
+        assert_eq!(
+            report.details,
+            "Could not validate: Miscellaneous Error oops"
+        );
+    }
+}
@@ -0,0 +1,905 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use super::driver::{Event, Phase, State};
+use super::error;
+use super::history;
+
+// The transitions of the FSM, as a table: for a state and an event, where the FSM goes, provided
+// the guard lets it. The first transition which applies wins, and an event no transition expects
+// is a failure. The table is checked when the program starts.
+
+// How many times we check Elasticsearch before giving up on indexing.
+pub const CLUSTER_CHECK_ATTEMPTS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StateKind {
+    NotAvailable,
+    DownloadingInProgress,
+    DownloadingError,
+    Downloaded,
+    ProcessingInProgress,
+    ProcessingError,
+    Processed,
+    WaitingForCluster,
+    IndexingInProgress,
+    IndexingError,
+    Indexed,
+    ValidationInProgress,
+    ValidationError,
+    Available,
+    RollbackInProgress,
+    RollbackError,
+    RolledBack,
+    Failed,
+    Paused,
+    Cancelled,
+    Failure,
+}
+
+impl StateKind {
+    pub const ALL: [StateKind; 21] = [
+        StateKind::NotAvailable,
+        StateKind::DownloadingInProgress,
+        StateKind::DownloadingError,
+        StateKind::Downloaded,
+        StateKind::ProcessingInProgress,
+        StateKind::ProcessingError,
+        StateKind::Processed,
+        StateKind::WaitingForCluster,
+        StateKind::IndexingInProgress,
+        StateKind::IndexingError,
+        StateKind::Indexed,
+        StateKind::ValidationInProgress,
+        StateKind::ValidationError,
+        StateKind::Available,
+        StateKind::RollbackInProgress,
+        StateKind::RollbackError,
+        StateKind::RolledBack,
+        StateKind::Failed,
+        StateKind::Paused,
+        StateKind::Cancelled,
+        StateKind::Failure,
+    ];
+
+    pub const INITIAL: StateKind = StateKind::NotAvailable;
+
+    // The FSM stops there.
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            StateKind::Available | StateKind::Failed | StateKind::Cancelled | StateKind::Failure
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Download,
+    DownloadingError,
+    DownloadingComplete,
+    Process,
+    ProcessingError,
+    ProcessingComplete,
+    ClusterUnavailable,
+    Index,
+    IndexingError,
+    IndexingComplete,
+    Validate,
+    ValidationError,
+    ValidationComplete,
+    Rollback,
+    RollbackError,
+    RollbackComplete,
+    Timeout,
+    Fail,
+    Retry,
+    Pause,
+    Resume,
+    Cancel,
+}
+
+impl EventKind {
+    // Sent by clients, rather than by the work of the FSM.
+    pub fn is_command(self) -> bool {
+        matches!(
+            self,
+            EventKind::Pause | EventKind::Resume | EventKind::Cancel
+        )
+    }
+}
+
+impl State {
+    pub fn kind(&self) -> StateKind {
+        match self {
+            State::NotAvailable => StateKind::NotAvailable,
+            State::DownloadingInProgress { .. } => StateKind::DownloadingInProgress,
+            State::DownloadingError { .. } => StateKind::DownloadingError,
+            State::Downloaded { .. } => StateKind::Downloaded,
+            State::ProcessingInProgress { .. } => StateKind::ProcessingInProgress,
+            State::ProcessingError { .. } => StateKind::ProcessingError,
+            State::Processed { .. } => StateKind::Processed,
+            State::WaitingForCluster { .. } => StateKind::WaitingForCluster,
+            State::IndexingInProgress { .. } => StateKind::IndexingInProgress,
+            State::IndexingError { .. } => StateKind::IndexingError,
+            State::Indexed { .. } => StateKind::Indexed,
+            State::ValidationInProgress { .. } => StateKind::ValidationInProgress,
+            State::ValidationError { .. } => StateKind::ValidationError,
+            State::Available { .. } => StateKind::Available,
+            State::RollbackInProgress { .. } => StateKind::RollbackInProgress,
+            State::RollbackError { .. } => StateKind::RollbackError,
+            State::RolledBack { .. } => StateKind::RolledBack,
+            State::Failed { .. } => StateKind::Failed,
+            State::Paused { .. } => StateKind::Paused,
+            State::Cancelled => StateKind::Cancelled,
+            State::Failure(_) => StateKind::Failure,
+        }
+    }
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Download => EventKind::Download,
+            Event::DownloadingError(_) => EventKind::DownloadingError,
+            Event::DownloadingComplete(..) => EventKind::DownloadingComplete,
+            Event::Process(_) => EventKind::Process,
+            Event::ProcessingError(_) => EventKind::ProcessingError,
+            Event::ProcessingComplete(..) => EventKind::ProcessingComplete,
+            Event::ClusterUnavailable(..) => EventKind::ClusterUnavailable,
+            Event::Index(_) => EventKind::Index,
+            Event::IndexingError(_) => EventKind::IndexingError,
+            Event::IndexingComplete(_) => EventKind::IndexingComplete,
+            Event::Validate => EventKind::Validate,
+            Event::ValidationError(_) => EventKind::ValidationError,
+            Event::ValidationComplete(..) => EventKind::ValidationComplete,
+            Event::Rollback => EventKind::Rollback,
+            Event::RollbackError(_) => EventKind::RollbackError,
+            Event::RollbackComplete(_) => EventKind::RollbackComplete,
+            Event::Timeout(_) => EventKind::Timeout,
+            Event::Fail => EventKind::Fail,
+            Event::Retry => EventKind::Retry,
+            Event::Pause => EventKind::Pause,
+            Event::Resume => EventKind::Resume,
+            Event::Cancel => EventKind::Cancel,
+        }
+    }
+}
+
+// Where a transition leads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    State(StateKind),
+    Same,    // The FSM stays where it is
+    Resumed, // The state the FSM was in when it was paused
+}
+
+pub type Guard = fn(&State, &Event) -> bool;
+pub type Action = fn(&State, Event) -> State;
+
+pub struct Transition {
+    pub from: Vec<StateKind>,
+    pub event: EventKind,
+    pub to: Target,
+    pub guard: Option<Guard>,
+    pub action: Action, // Builds the new state, and must agree with to
+}
+
+impl Transition {
+    fn new(from: &[StateKind], event: EventKind, to: StateKind, action: Action) -> Self {
+        Transition {
+            from: from.to_vec(),
+            event,
+            to: Target::State(to),
+            guard: None,
+            action,
+        }
+    }
+
+    fn guarded(mut self, guard: Guard) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    fn applies(&self, state: &State, event: &Event) -> bool {
+        self.event == event.kind()
+            && self.from.contains(&state.kind())
+            && self.guard.map(|guard| guard(state, event)).unwrap_or(true)
+    }
+}
+
+// The states the FSM can pause in: a job which is over can't be paused, nor paused twice.
+fn pausable() -> Vec<StateKind> {
+    StateKind::ALL
+        .iter()
+        .cloned()
+        .filter(|kind| *kind != StateKind::Paused && !kind.is_terminal())
+        .collect()
+}
+
+// A job which is over can't be cancelled, a paused one can.
+fn cancellable() -> Vec<StateKind> {
+    StateKind::ALL
+        .iter()
+        .cloned()
+        .filter(|kind| !kind.is_terminal())
+        .collect()
+}
+
+// Resuming a job which isn't paused does nothing.
+fn resumable() -> Vec<StateKind> {
+    StateKind::ALL
+        .iter()
+        .cloned()
+        .filter(|kind| *kind != StateKind::Paused)
+        .collect()
+}
+
+// How many times we checked Elasticsearch.
+fn cluster_checks(state: &State) -> u32 {
+    match state {
+        State::WaitingForCluster { attempts, .. } => *attempts,
+        _ => 0,
+    }
+}
+
+// The attempt of the phase in progress. An error before the phase started counts as its first
+// attempt, and an error while waiting for the cluster as the attempt we wait for.
+fn attempt(state: &State) -> u32 {
+    match state {
+        State::DownloadingInProgress { attempt, .. }
+        | State::ProcessingInProgress { attempt, .. }
+        | State::WaitingForCluster { attempt, .. }
+        | State::IndexingInProgress { attempt, .. }
+        | State::ValidationInProgress { attempt } => *attempt,
+        _ => 1,
+    }
+}
+
+// How many attempts of a phase failed.
+fn attempts(state: &State) -> u32 {
+    match state {
+        State::DownloadingError { attempts, .. }
+        | State::ProcessingError { attempts, .. }
+        | State::IndexingError { attempts, .. }
+        | State::ValidationError { attempts, .. } => *attempts,
+        _ => 0,
+    }
+}
+
+// The error which makes the FSM give up, and after how many attempts. Only a wrong transition
+// table gives up from a state without one, which is a Failure.
+fn failure(state: &State) -> Result<(error::Report, u32), State> {
+    match state {
+        State::DownloadingError { error, attempts }
+        | State::ProcessingError {
+            error, attempts, ..
+        }
+        | State::IndexingError {
+            error, attempts, ..
+        }
+        | State::ValidationError { error, attempts } => Ok((error.clone(), *attempts)),
+        State::RollbackInProgress { cause, attempts }
+        | State::RolledBack {
+            cause, attempts, ..
+        } => Ok((cause.clone(), *attempts)),
+        State::RollbackError {
+            details,
+            cause,
+            attempts,
+        } => {
+            let mut error = cause.clone();
+            error.details = format!("{} (rollback failed: {})", cause.details, details);
+            Ok((error, *attempts))
+        }
+        state => Err(State::Failure(format!("Unexpected failure in {:?}", state))),
+    }
+}
+
+// What went wrong with a phase which took too long.
+fn timed_out(phase: Phase, timeout: Duration) -> error::Report {
+    error::Report::message(
+        phase,
+        error::Kind::Timeout,
+        format!("Timed out after {}", history::format_duration(timeout)),
+    )
+}
+
+// The file the state is about, if any.
+fn file_path(state: &State) -> PathBuf {
+    match state {
+        State::Downloaded { file_path, .. }
+        | State::ProcessingInProgress { file_path, .. }
+        | State::ProcessingError { file_path, .. }
+        | State::Processed { file_path, .. }
+        | State::WaitingForCluster { file_path, .. }
+        | State::IndexingInProgress { file_path, .. }
+        | State::IndexingError { file_path, .. } => file_path.clone(),
+        _ => PathBuf::new(),
+    }
+}
+
+pub fn table() -> Vec<Transition> {
+    use EventKind as E;
+    use StateKind as S;
+    vec![
+        Transition::new(
+            // A job which failed can start over
+            &[S::NotAvailable, S::Failed],
+            E::Download,
+            S::DownloadingInProgress,
+            |_, _| State::DownloadingInProgress {
+                started_at: SystemTime::now(),
+                attempt: 1,
+            },
+        ),
+        Transition::new(
+            &[S::DownloadingInProgress],
+            E::DownloadingError,
+            S::DownloadingError,
+            |state, event| match event {
+                Event::DownloadingError(error) => State::DownloadingError {
+                    error,
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::DownloadingInProgress],
+            E::DownloadingComplete,
+            S::Downloaded,
+            |_, event| match event {
+                Event::DownloadingComplete(file_path, duration) => State::Downloaded {
+                    file_path,
+                    duration,
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::Downloaded],
+            E::Process,
+            S::ProcessingInProgress,
+            |_, event| match event {
+                Event::Process(file_path) => State::ProcessingInProgress {
+                    file_path,
+                    started_at: SystemTime::now(),
+                    attempt: 1,
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::ProcessingInProgress],
+            E::ProcessingError,
+            S::ProcessingError,
+            |state, event| match event {
+                Event::ProcessingError(error) => State::ProcessingError {
+                    file_path: file_path(state),
+                    error,
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::ProcessingInProgress],
+            E::ProcessingComplete,
+            S::Processed,
+            |_, event| match event {
+                Event::ProcessingComplete(file_path, duration) => State::Processed {
+                    file_path,
+                    duration,
+                },
+                event => unexpected(event),
+            },
+        ),
+        // Data sources which don't need processing go straight from Downloaded to indexing.
+        Transition::new(
+            &[S::Downloaded, S::Processed, S::WaitingForCluster],
+            E::Index,
+            S::IndexingInProgress,
+            |state, event| match event {
+                Event::Index(file_path) => State::IndexingInProgress {
+                    file_path,
+                    started_at: SystemTime::now(),
+                    attempt: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::Downloaded, S::Processed, S::WaitingForCluster],
+            E::ClusterUnavailable,
+            S::WaitingForCluster,
+            |state, event| match event {
+                Event::ClusterUnavailable(file_path, details) => State::WaitingForCluster {
+                    file_path,
+                    details,
+                    attempts: cluster_checks(state) + 1,
+                    attempt: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        )
+        .guarded(|state, _| cluster_checks(state) < CLUSTER_CHECK_ATTEMPTS),
+        // Before another attempt, the driver checks the cluster again, which may not be ready
+        Transition::new(
+            &[S::IndexingError],
+            E::ClusterUnavailable,
+            S::WaitingForCluster,
+            |state, event| match event {
+                Event::ClusterUnavailable(file_path, details) => State::WaitingForCluster {
+                    file_path,
+                    details,
+                    attempts: 1,
+                    attempt: attempts(state) + 1,
+                },
+                event => unexpected(event),
+            },
+        ),
+        // We waited long enough
+        Transition::new(
+            &[S::WaitingForCluster],
+            E::ClusterUnavailable,
+            S::IndexingError,
+            |state, event| match event {
+                Event::ClusterUnavailable(file_path, details) => State::IndexingError {
+                    file_path,
+                    error: error::Report::message(
+                        Phase::Indexing,
+                        error::Kind::Elasticsearch,
+                        format!(
+                            "Elasticsearch still unavailable after {} attempts: {}",
+                            cluster_checks(state),
+                            details
+                        ),
+                    ),
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[
+                S::Downloaded,
+                S::Processed,
+                S::WaitingForCluster,
+                S::IndexingInProgress,
+            ],
+            E::IndexingError,
+            S::IndexingError,
+            |state, event| match event {
+                Event::IndexingError(error) => State::IndexingError {
+                    file_path: file_path(state),
+                    error,
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::IndexingInProgress],
+            E::IndexingComplete,
+            S::Indexed,
+            |_, event| match event {
+                Event::IndexingComplete(duration) => State::Indexed { duration },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::Indexed],
+            E::Validate,
+            S::ValidationInProgress,
+            |_, _| State::ValidationInProgress { attempt: 1 },
+        ),
+        Transition::new(
+            &[S::ValidationInProgress],
+            E::ValidationError,
+            S::ValidationError,
+            |state, event| match event {
+                Event::ValidationError(error) => State::ValidationError {
+                    error,
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::ValidationInProgress],
+            E::ValidationComplete,
+            S::Available,
+            |_, event| match event {
+                Event::ValidationComplete(index, alias) => State::Available { index, alias },
+                event => unexpected(event),
+            },
+        ),
+        // The watchdog stopped a phase which took too long: it's an error of that phase, which
+        // may be tried again.
+        Transition::new(
+            &[S::DownloadingInProgress],
+            E::Timeout,
+            S::DownloadingError,
+            |state, event| match event {
+                Event::Timeout(timeout) => State::DownloadingError {
+                    error: timed_out(Phase::Download, timeout),
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::ProcessingInProgress],
+            E::Timeout,
+            S::ProcessingError,
+            |state, event| match event {
+                Event::Timeout(timeout) => State::ProcessingError {
+                    file_path: file_path(state),
+                    error: timed_out(Phase::Processing, timeout),
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::WaitingForCluster, S::IndexingInProgress],
+            E::Timeout,
+            S::IndexingError,
+            |state, event| match event {
+                Event::Timeout(timeout) => State::IndexingError {
+                    file_path: file_path(state),
+                    error: timed_out(Phase::Indexing, timeout),
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::ValidationInProgress],
+            E::Timeout,
+            S::ValidationError,
+            |state, event| match event {
+                Event::Timeout(timeout) => State::ValidationError {
+                    error: timed_out(Phase::Validation, timeout),
+                    attempts: attempt(state),
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::IndexingError, S::ValidationError],
+            E::Rollback,
+            S::RollbackInProgress,
+            |state, _| match failure(state) {
+                Ok((cause, attempts)) => State::RollbackInProgress { cause, attempts },
+                Err(failure) => failure,
+            },
+        ),
+        Transition::new(
+            &[S::RollbackInProgress],
+            E::RollbackError,
+            S::RollbackError,
+            |state, event| match event {
+                Event::RollbackError(details) => match failure(state) {
+                    Ok((cause, attempts)) => State::RollbackError {
+                        details,
+                        cause,
+                        attempts,
+                    },
+                    Err(failure) => failure,
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[S::RollbackInProgress],
+            E::RollbackComplete,
+            S::RolledBack,
+            |state, event| match event {
+                Event::RollbackComplete(indices) => match failure(state) {
+                    Ok((cause, attempts)) => State::RolledBack {
+                        indices,
+                        cause,
+                        attempts,
+                    },
+                    Err(failure) => failure,
+                },
+                event => unexpected(event),
+            },
+        ),
+        Transition::new(
+            &[
+                S::DownloadingError,
+                S::ProcessingError,
+                S::RollbackError,
+                S::RolledBack,
+            ],
+            E::Fail,
+            S::Failed,
+            |state, _| match failure(state) {
+                Ok((error, attempts)) => State::Failed { error, attempts },
+                Err(failure) => failure,
+            },
+        ),
+        // The driver decides whether a phase which failed is tried again, according to its
+        // recovery policy. If not, it gives up with a Fail or a Rollback.
+        Transition::new(
+            &[S::DownloadingError],
+            E::Retry,
+            S::DownloadingInProgress,
+            |state, _| State::DownloadingInProgress {
+                started_at: SystemTime::now(),
+                attempt: attempts(state) + 1,
+            },
+        ),
+        Transition::new(
+            &[S::ProcessingError],
+            E::Retry,
+            S::ProcessingInProgress,
+            |state, _| State::ProcessingInProgress {
+                file_path: file_path(state),
+                started_at: SystemTime::now(),
+                attempt: attempts(state) + 1,
+            },
+        ),
+        // With the file we already have
+        Transition::new(
+            &[S::IndexingError],
+            E::Retry,
+            S::IndexingInProgress,
+            |state, _| State::IndexingInProgress {
+                file_path: file_path(state),
+                started_at: SystemTime::now(),
+                attempt: attempts(state) + 1,
+            },
+        ),
+        Transition::new(
+            &[S::ValidationError],
+            E::Retry,
+            S::ValidationInProgress,
+            |state, _| State::ValidationInProgress {
+                attempt: attempts(state) + 1,
+            },
+        ),
+        // Commands
+        Transition {
+            from: vec![S::Paused],
+            event: E::Resume,
+            to: Target::Resumed,
+            guard: None,
+            action: |state, _| match state {
+                State::Paused { state } => (**state).clone(),
+                state => state.clone(),
+            },
+        },
+        Transition {
+            from: vec![S::Paused],
+            event: E::Pause,
+            to: Target::Same,
+            guard: None,
+            action: |state, _| state.clone(),
+        },
+        // Not paused, nothing to resume
+        Transition {
+            from: resumable(),
+            event: E::Resume,
+            to: Target::Same,
+            guard: None,
+            action: |state, _| state.clone(),
+        },
+        Transition {
+            from: pausable(),
+            event: E::Pause,
+            to: Target::State(S::Paused),
+            guard: None,
+            action: |state, _| State::Paused {
+                state: Box::new(state.clone()),
+            },
+        },
+        Transition::new(&cancellable(), E::Cancel, S::Cancelled, |_, _| {
+            State::Cancelled
+        }),
+    ]
+}
+
+// An action got an event its transition doesn't expect, which validate can't catch.
+fn unexpected(event: Event) -> State {
+    State::Failure(format!("Unexpected event {:?}", event))
+}
+
+// Where the FSM goes from state, on event.
+pub fn next(table: &[Transition], state: &State, event: Event) -> State {
+    match table
+        .iter()
+        .find(|transition| transition.applies(state, &event))
+    {
+        Some(transition) => (transition.action)(state, event),
+        None => State::Failure(format!(
+            "Wrong state, event combination: {:#?} {:#?}",
+            state, event
+        )),
+    }
+}
+
+// Checks that every state can be reached from the initial state, and that the FSM can leave
+// every state which is not terminal, without relying on commands.
+pub fn validate(table: &[Transition]) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+
+    let mut reached = HashSet::new();
+    let mut queue = VecDeque::new();
+    reached.insert(StateKind::INITIAL);
+    queue.push_back(StateKind::INITIAL);
+    // Where the events no transition expects lead
+    reached.insert(StateKind::Failure);
+    while let Some(kind) = queue.pop_front() {
+        for transition in table.iter().filter(|t| t.from.contains(&kind)) {
+            if let Target::State(to) = transition.to {
+                if reached.insert(to) {
+                    queue.push_back(to);
+                }
+            }
+        }
+    }
+    for kind in StateKind::ALL.iter() {
+        if !reached.contains(kind) {
+            problems.push(format!("{:?} can't be reached", kind));
+        }
+    }
+
+    for kind in StateKind::ALL.iter().filter(|kind| !kind.is_terminal()) {
+        let leaves = table.iter().any(|t| {
+            !t.event.is_command()
+                && t.from.contains(kind)
+                && match t.to {
+                    Target::State(to) => to != *kind,
+                    _ => false,
+                }
+        });
+        if !leaves && *kind != StateKind::Paused {
+            problems.push(format!("{:?} is a dead end", kind));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::Phase;
+
+    #[test]
+    fn table_is_valid() {
+        assert_eq!(validate(&table()), Ok(()));
+    }
+
+    #[test]
+    fn pauses_running_jobs_only() {
+        let table = table();
+        let running = State::DownloadingInProgress {
+            started_at: SystemTime::now(),
+            attempt: 1,
+        };
+        assert_eq!(
+            next(&table, &running, Event::Pause),
+            State::Paused {
+                state: Box::new(running.clone())
+            }
+        );
+        let error = error::Report::message(Phase::Download, error::Kind::Unsupported, "oops");
+        let over = vec![
+            State::Available {
+                index: String::from("index"),
+                alias: String::from("alias"),
+            },
+            State::Failed { error, attempts: 1 },
+            State::Cancelled,
+            State::Failure(String::from("oops")),
+        ];
+        for state in over {
+            assert!(state.kind().is_terminal());
+            assert!(!table
+                .iter()
+                .any(|transition| transition.applies(&state, &Event::Pause)));
+            // Resuming a job which isn't paused does nothing
+            assert_eq!(next(&table, &state, Event::Resume), state);
+        }
+    }
+
+    #[test]
+    fn cancels_unfinished_jobs_only() {
+        let table = table();
+        let running = State::DownloadingInProgress {
+            started_at: SystemTime::now(),
+            attempt: 1,
+        };
+        let paused = State::Paused {
+            state: Box::new(running.clone()),
+        };
+        for state in [running, paused].iter() {
+            assert_eq!(next(&table, state, Event::Cancel), State::Cancelled);
+        }
+        let error = error::Report::message(Phase::Download, error::Kind::Unsupported, "oops");
+        let over = vec![
+            State::Available {
+                index: String::from("index"),
+                alias: String::from("alias"),
+            },
+            State::Failed { error, attempts: 1 },
+            State::Cancelled,
+            State::Failure(String::from("oops")),
+        ];
+        for state in over {
+            assert!(!table
+                .iter()
+                .any(|transition| transition.applies(&state, &Event::Cancel)));
+        }
+    }
+
+    #[test]
+    fn gives_up_on_cluster_as_first_indexing_attempt() {
+        let waiting = State::WaitingForCluster {
+            file_path: PathBuf::from("fr.osm.pbf"),
+            details: String::from("red"),
+            attempts: CLUSTER_CHECK_ATTEMPTS,
+            attempt: 1,
+        };
+        let event = Event::ClusterUnavailable(PathBuf::from("fr.osm.pbf"), String::from("red"));
+        match next(&table(), &waiting, event) {
+            State::IndexingError {
+                error, attempts, ..
+            } => {
+                assert_eq!(error.phase, Phase::Indexing);
+                assert_eq!(attempts, 1);
+            }
+            state => panic!("Expected IndexingError, got {:?}", state),
+        }
+    }
+
+    #[test]
+    fn retries_indexing_once_the_cluster_is_ready() {
+        let failed = State::IndexingError {
+            file_path: PathBuf::from("fr.osm.pbf"),
+            error: error::Report::message(Phase::Indexing, error::Kind::Elasticsearch, "red"),
+            attempts: 1,
+        };
+        let event = Event::ClusterUnavailable(PathBuf::from("fr.osm.pbf"), String::from("red"));
+        let waiting = next(&table(), &failed, event);
+        match &waiting {
+            State::WaitingForCluster {
+                attempts, attempt, ..
+            } => assert_eq!((*attempts, *attempt), (1, 2)),
+            state => panic!("Expected WaitingForCluster, got {:?}", state),
+        }
+        match next(
+            &table(),
+            &waiting,
+            Event::Index(PathBuf::from("fr.osm.pbf")),
+        ) {
+            State::IndexingInProgress { attempt, .. } => assert_eq!(attempt, 2),
+            state => panic!("Expected IndexingInProgress, got {:?}", state),
+        }
+    }
+
+    #[test]
+    fn gives_up_without_error_as_failure() {
+        match failure(&State::NotAvailable) {
+            Err(State::Failure(details)) => assert!(details.contains("NotAvailable")),
+            other => panic!("Expected a Failure, got {:?}", other),
+        }
+        let error = error::Report::message(Phase::Processing, error::Kind::IO, "oops");
+        let state = State::ProcessingError {
+            file_path: PathBuf::from("fr.json"),
+            error: error.clone(),
+            attempts: 2,
+        };
+        assert_eq!(failure(&state), Ok((error, 2)));
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt::Write;
+
+use super::fsm::{StateKind, Target, Transition};
+
+// Draws the transition table of the FSM, for Graphviz (DOT) or Mermaid, so that the picture in
+// the docs is the FSM we actually run.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Dot,
+    Mermaid,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Edge {
+    from: StateKind,
+    to: StateKind,
+    label: String,
+}
+
+// Commands can be sent in (almost) any state, so they'd clutter the picture: we only draw them
+// when asked to. A resumed FSM goes back where it was paused, which we can't draw either.
+fn edges(table: &[Transition], commands: bool) -> Vec<Edge> {
+    let mut edges = Vec::new();
+    for transition in table {
+        if transition.event.is_command() && !commands {
+            continue;
+        }
+        let to = match transition.to {
+            Target::State(to) => to,
+            Target::Same | Target::Resumed => continue,
+        };
+        let label = match transition.guard {
+            Some(_) => format!("{:?} [guard]", transition.event),
+            None => format!("{:?}", transition.event),
+        };
+        for from in &transition.from {
+            edges.push(Edge {
+                from: *from,
+                to,
+                label: label.clone(),
+            });
+        }
+    }
+    edges
+}
+
+// An edge between two consecutive states of the path.
+fn highlighted(edge: &Edge, path: &[String]) -> bool {
+    let from = format!("{:?}", edge.from);
+    let to = format!("{:?}", edge.to);
+    path.windows(2).any(|pair| pair[0] == from && pair[1] == to)
+}
+
+fn visited(state: StateKind, path: &[String]) -> bool {
+    let name = format!("{:?}", state);
+    path.contains(&name)
+}
+
+// Without commands, the states only commands lead to are left out.
+fn states(edges: &[Edge]) -> Vec<StateKind> {
+    StateKind::ALL
+        .iter()
+        .cloned()
+        .filter(|state| {
+            *state == StateKind::INITIAL
+                || edges
+                    .iter()
+                    .any(|edge| edge.from == *state || edge.to == *state)
+        })
+        .collect()
+}
+
+pub fn render(table: &[Transition], format: Format, commands: bool, path: &[String]) -> String {
+    let edges = edges(table, commands);
+    match format {
+        Format::Dot => dot(&edges, path),
+        Format::Mermaid => mermaid(&edges, path),
+    }
+}
+
+fn dot(edges: &[Edge], path: &[String]) -> String {
+    let mut out = String::new();
+    // Writing to a String can't fail
+    let _ = writeln!(out, "digraph fsm {{");
+    let _ = writeln!(out, "    rankdir=LR;");
+    let _ = writeln!(out, "    node [shape=box, style=rounded];");
+    for state in states(edges) {
+        let mut attributes = Vec::new();
+        if state == StateKind::INITIAL {
+            attributes.push("penwidth=2");
+        }
+        if state.is_terminal() {
+            attributes.push("peripheries=2");
+        }
+        if visited(state, path) {
+            attributes.push("color=red, fontcolor=red");
+        }
+        if attributes.is_empty() {
+            let _ = writeln!(out, "    {:?};", state);
+        } else {
+            let _ = writeln!(out, "    {:?} [{}];", state, attributes.join(", "));
+        }
+    }
+    for edge in edges {
+        let highlight = if highlighted(edge, path) {
+            ", color=red, fontcolor=red, penwidth=2"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            out,
+            "    {:?} -> {:?} [label=\"{}\"{}];",
+            edge.from, edge.to, edge.label, highlight
+        );
+    }
+    let _ = writeln!(out, "}}");
+    out
+}
+
+fn mermaid(edges: &[Edge], path: &[String]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "flowchart LR");
+    for state in states(edges) {
+        if state.is_terminal() {
+            let _ = writeln!(out, "    {:?}([{:?}])", state, state);
+        } else {
+            let _ = writeln!(out, "    {:?}[{:?}]", state, state);
+        }
+    }
+    for edge in edges {
+        let _ = writeln!(
+            out,
+            "    {:?} -->|\"{}\"| {:?}",
+            edge.from, edge.label, edge.to
+        );
+    }
+    // Mermaid styles links by their position
+    for (i, edge) in edges.iter().enumerate() {
+        if highlighted(edge, path) {
+            let _ = writeln!(out, "    linkStyle {} stroke:red,stroke-width:3px", i);
+        }
+    }
+    let on_path: Vec<String> = states(edges)
+        .into_iter()
+        .filter(|state| visited(*state, path))
+        .map(|state| format!("{:?}", state))
+        .collect();
+    if !on_path.is_empty() {
+        let _ = writeln!(out, "    classDef visited stroke:red,stroke-width:3px");
+        let _ = writeln!(out, "    class {} visited", on_path.join(","));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsm;
+
+    fn path() -> Vec<String> {
+        ["NotAvailable", "DownloadingInProgress", "Downloaded"]
+            .iter()
+            .map(|state| String::from(*state))
+            .collect()
+    }
+
+    #[test]
+    fn dot_highlights_the_path() {
+        let dot = render(&fsm::table(), Format::Dot, false, &path());
+        let lines: Vec<&str> = dot.lines().collect();
+        assert!(lines.contains(
+            &"    NotAvailable -> DownloadingInProgress \
+              [label=\"Download\", color=red, fontcolor=red, penwidth=2];"
+        ));
+        assert!(lines.contains(&"    Downloaded -> ProcessingInProgress [label=\"Process\"];"));
+        assert!(lines.contains(&"    Downloaded [color=red, fontcolor=red];"));
+        // Commands are left out
+        assert!(!dot.contains("Cancel"));
+    }
+
+    #[test]
+    fn mermaid_highlights_the_path() {
+        let mermaid = render(&fsm::table(), Format::Mermaid, false, &path());
+        let links: Vec<&str> = mermaid
+            .lines()
+            .filter(|line| line.contains("-->"))
+            .collect();
+        let download = links
+            .iter()
+            .position(|link| *link == "    NotAvailable -->|\"Download\"| DownloadingInProgress")
+            .unwrap();
+        let process = links
+            .iter()
+            .position(|link| *link == "    Downloaded -->|\"Process\"| ProcessingInProgress")
+            .unwrap();
+        let styled = |i: usize| format!("    linkStyle {} stroke:red,stroke-width:3px", i);
+        assert!(mermaid.lines().any(|line| line == styled(download)));
+        assert!(!mermaid.lines().any(|line| line == styled(process)));
+        assert!(mermaid
+            .lines()
+            .any(|line| line == "    class NotAvailable,DownloadingInProgress,Downloaded visited"));
+    }
+}
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::driver::State;
+use super::endpoint;
+use super::error;
+
+// Once a job is over, we keep a record of how it went, one JSON object per line, so that we can
+// look back at past runs.
+
+pub const PATH: &str = "./work/history.jsonl";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Record {
+    pub job_id: String,
+    pub data_source: String,
+    pub region: String,
+    pub index_type: String,
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    pub download: Option<Duration>,
+    pub download_size: Option<u64>, // In bytes, if the file is still there when the job ends
+    pub processing: Option<Duration>,
+    pub processed_size: Option<u64>,
+    pub indexing: Option<Duration>,
+    pub outcome: String, // The last state, eg Available, Cancelled, ...
+    pub errors: Vec<String>,
+    #[serde(default)]
+    pub path: Vec<String>, // The states the job went through
+}
+
+impl Record {
+    // What the history of a job tells us about it, None if the job didn't publish anything.
+    pub fn from_status(status: &endpoint::Status) -> Option<Record> {
+        let current = status.current.as_ref()?;
+        let first = status.history.first()?;
+        let mut record = Record {
+            job_id: current.job_id.clone(),
+            data_source: current.data_source.clone(),
+            region: current.region.clone(),
+            index_type: current.index_type.clone(),
+            started_at: first.timestamp,
+            finished_at: current.timestamp,
+            download: None,
+            download_size: None,
+            processing: None,
+            processed_size: None,
+            indexing: None,
+            outcome: name(&current.state),
+            errors: Vec::new(),
+            path: status
+                .history
+                .iter()
+                .map(|transition| name(&transition.state))
+                .collect(),
+        };
+        for transition in &status.history {
+            match &transition.state {
+                State::Downloaded {
+                    file_path,
+                    duration,
+                } => {
+                    record.download = Some(*duration);
+                    record.download_size = file_size(file_path);
+                }
+                State::Processed {
+                    file_path,
+                    duration,
+                } => {
+                    record.processing = Some(*duration);
+                    record.processed_size = file_size(file_path);
+                }
+                State::Indexed { duration } => {
+                    record.indexing = Some(*duration);
+                }
+                State::DownloadingError { error, .. }
+                | State::ProcessingError { error, .. }
+                | State::IndexingError { error, .. }
+                | State::ValidationError { error, .. } => {
+                    record.errors.push(error.details.clone());
+                }
+                State::RollbackError { details, .. } | State::Failure(details) => {
+                    record.errors.push(details.clone());
+                }
+                _ => {}
+            }
+        }
+        Some(record)
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.finished_at
+            .duration_since(self.started_at)
+            .unwrap_or_default()
+    }
+}
+
+// The name of the state, without its data.
+fn name(state: &State) -> String {
+    format!("{:?}", state.kind())
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    std::fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        History { path: path.into() }
+    }
+
+    pub fn append(&self, record: &Record) -> Result<(), error::Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).context(error::IOError {
+                details: format!("Could not create directory {}", dir.display()),
+            })?;
+        }
+        let mut line = serde_json::to_string(record).context(error::SerdeJSONError {
+            details: String::from("Could not serialize history record"),
+        })?;
+        line.push('\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .context(error::IOError {
+                details: format!("Could not write to history {}", self.path.display()),
+            })
+    }
+
+    // All the records, oldest first. Lines we can't read are skipped.
+    pub fn records(&self) -> Result<Vec<Record>, error::Error> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = std::fs::read_to_string(&self.path).context(error::IOError {
+            details: format!("Could not read history {}", self.path.display()),
+        })?;
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub data_source: Option<String>,
+    pub region: Option<String>,
+    pub index_type: Option<String>,
+    pub outcome: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        fn matches(expected: &Option<String>, value: &str) -> bool {
+            expected
+                .as_ref()
+                .map(|expected| expected.eq_ignore_ascii_case(value))
+                .unwrap_or(true)
+        }
+        matches(&self.data_source, &record.data_source)
+            && matches(&self.region, &record.region)
+            && matches(&self.index_type, &record.index_type)
+            && matches(&self.outcome, &record.outcome)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics {
+    pub count: usize,
+    pub min: Duration,
+    pub mean: Duration,
+    pub median: Duration,
+    pub max: Duration,
+}
+
+impl Statistics {
+    // None without any duration.
+    pub fn new(durations: &[Duration]) -> Option<Statistics> {
+        if durations.is_empty() {
+            return None;
+        }
+        let mut durations = durations.to_vec();
+        durations.sort();
+        let count = durations.len();
+        let total: Duration = durations.iter().sum();
+        Some(Statistics {
+            count,
+            min: durations[0],
+            mean: total / count as u32,
+            median: durations[count / 2],
+            max: durations[count - 1],
+        })
+    }
+}
+
+// eg 1h02m03s, 4m05s, 6s
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
@@ -0,0 +1,331 @@
+use futures::stream::StreamExt;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+#[cfg(feature = "zmq")]
+use hyper::service::{make_service_fn, service_fn};
+#[cfg(feature = "zmq")]
+use hyper::Server;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::collections::HashMap;
+#[cfg(feature = "zmq")]
+use std::convert::Infallible;
+#[cfg(feature = "zmq")]
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+
+use super::client::{self, Notification};
+use super::driver::Envelope;
+use super::error;
+use super::redact;
+
+// A bridge for those who don't speak ZeroMQ, eg a web dashboard. It follows what the drivers
+// publish, and serves it over HTTP: the last state of each job as JSON, and what the drivers
+// publish, as it comes, as server-sent events. States come in the same envelope as on ZeroMQ.
+//
+//   GET /jobs                  the last state of each job
+//   GET /jobs/:id              the last state of that job
+//   GET /jobs/:id/events       the states and heartbeats of that job
+//   GET /events                the states and heartbeats of all the jobs
+
+// How many events a slow client can fall behind before it misses some.
+const BACKLOG: usize = 256;
+
+// How long we serve the last state of a job which is over, before we forget it.
+const RETENTION: Duration = Duration::from_secs(3600);
+
+// A server-sent event.
+#[derive(Debug, Clone)]
+struct Event {
+    job_id: String,
+    name: &'static str, // 'state' or 'heartbeat'
+    data: String,       // JSON, which fits on a line
+}
+
+impl Event {
+    fn new<T: Serialize>(job_id: &str, name: &'static str, data: &T) -> Result<Self, error::Error> {
+        let data = serde_json::to_string(data).context(error::SerdeJSONError {
+            details: format!("Could not serialize {}", name),
+        })?;
+        Ok(Event {
+            job_id: String::from(job_id),
+            name,
+            data,
+        })
+    }
+
+    fn from_notification(notification: &Notification) -> Result<Self, error::Error> {
+        match notification {
+            Notification::State { envelope, .. } => Event::new(&envelope.job_id, "state", envelope),
+            Notification::Heartbeat(heartbeat) => {
+                Event::new(&heartbeat.job_id, "heartbeat", heartbeat)
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        Bytes::from(format!("event: {}\ndata: {}\n\n", self.name, self.data))
+    }
+}
+
+#[derive(Clone)]
+struct Bridge {
+    jobs: Arc<Mutex<HashMap<String, Envelope>>>, // The last state of each job
+    events: broadcast::Sender<Event>,            // To each client of a stream
+    redactor: redact::Redactor,                  // Masks what looks like a secret in errors
+}
+
+#[cfg(feature = "zmq")]
+pub async fn serve(endpoint: &str, topic: &str, address: SocketAddr) -> Result<(), error::Error> {
+    let (events, _) = broadcast::channel(BACKLOG);
+    let bridge = Bridge {
+        jobs: Arc::new(Mutex::new(HashMap::new())),
+        events,
+        redactor: redact::Redactor::new(),
+    };
+    let service = {
+        let bridge = bridge.clone();
+        make_service_fn(move |_| {
+            let bridge = bridge.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = bridge.respond(request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        })
+    };
+    let server = Server::try_bind(&address)
+        .context(error::HyperError {
+            details: format!("Could not listen on {}", address),
+        })?
+        .serve(service);
+    // Watching all the jobs never ends, so the server does first, on error.
+    tokio::select! {
+        result = server => result.context(error::HyperError {
+            details: String::from("Could not serve HTTP requests"),
+        }),
+        () = bridge.follow(client::watch(endpoint, topic)) => Ok(()),
+    }
+}
+
+impl Bridge {
+    async fn follow(&self, mut notifications: client::Notifications) {
+        while let Some(notification) = notifications.next().await {
+            let notification = match notification {
+                Ok(notification) => notification,
+                Err(err) => {
+                    // The client goes on after an error
+                    eprintln!("{}", self.redactor.redact(&format!("{}", err)));
+                    continue;
+                }
+            };
+            if let Notification::State { envelope, .. } = &notification {
+                self.record(envelope, SystemTime::now());
+            }
+            match Event::from_notification(&notification) {
+                // No one may be listening, which is fine
+                Ok(event) => {
+                    let _ = self.events.send(event);
+                }
+                Err(err) => eprintln!("{}", self.redactor.redact(&format!("{}", err))),
+            }
+        }
+    }
+
+    // Keeps the last state of the job, and forgets the jobs over for longer than RETENTION, so
+    // that a long running bridge doesn't keep every job it ever saw.
+    fn record(&self, envelope: &Envelope, now: SystemTime) {
+        if let Ok(mut jobs) = self.jobs.lock() {
+            jobs.insert(envelope.job_id.clone(), envelope.clone());
+            jobs.retain(|_, envelope| {
+                !envelope.state.kind().is_terminal()
+                    || now
+                        .duration_since(envelope.timestamp)
+                        .map(|age| age < RETENTION)
+                        .unwrap_or(true)
+            });
+        }
+    }
+
+    fn respond(&self, request: Request<Body>) -> Response<Body> {
+        if request.method() != Method::GET {
+            return status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let path: Vec<&str> = request.uri().path().trim_matches('/').split('/').collect();
+        match path.as_slice() {
+            ["jobs"] => self.jobs(),
+            ["jobs", job_id] => self.job(job_id),
+            ["jobs", job_id, "events"] => self.stream(Some(String::from(*job_id))),
+            ["events"] => self.stream(None),
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn jobs(&self) -> Response<Body> {
+        let mut jobs: Vec<Envelope> = match self.jobs.lock() {
+            Ok(jobs) => jobs.values().cloned().collect(),
+            Err(_) => return status(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        jobs.sort_by(|a, b| a.job_id.cmp(&b.job_id));
+        json(&jobs)
+    }
+
+    fn job(&self, job_id: &str) -> Response<Body> {
+        let job = match self.jobs.lock() {
+            Ok(jobs) => jobs.get(job_id).cloned(),
+            Err(_) => return status(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        match job {
+            Some(envelope) => json(&envelope),
+            None => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    // Server-sent events, for one job or all of them, starting with their last state, so that a
+    // client doesn't wait for the next one.
+    fn stream(&self, job_id: Option<String>) -> Response<Body> {
+        let matches = move |id: &str| job_id.as_ref().map(|job_id| job_id == id).unwrap_or(true);
+        let mut events = self.events.subscribe();
+        let current: Vec<Event> = match self.jobs.lock() {
+            Ok(jobs) => jobs
+                .values()
+                .filter(|envelope| matches(&envelope.job_id))
+                .filter_map(|envelope| Event::new(&envelope.job_id, "state", envelope).ok())
+                .collect(),
+            Err(_) => return status(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for event in current {
+                if sender.send_data(event.to_bytes()).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                match events.recv().await {
+                    Ok(event) if matches(&event.job_id) => {
+                        // Unless the client is gone
+                        if sender.send_data(event.to_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    // The client misses what it was too slow to receive, but we go on
+                    Err(broadcast::RecvError::Lagged(_)) => {}
+                    Err(broadcast::RecvError::Closed) => return,
+                }
+            }
+        });
+        let mut response = Response::new(body);
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        response
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(json) => {
+            let mut response = Response::new(Body::from(json));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(_) => status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(code.canonical_reason().unwrap_or_default()));
+    *response.status_mut() = code;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::State;
+
+    fn envelope(job_id: &str, state: State, timestamp: SystemTime) -> Envelope {
+        Envelope {
+            job_id: String::from(job_id),
+            sequence: 0,
+            timestamp,
+            data_source: String::from("osm"),
+            region: String::from("fr"),
+            index_type: String::from("admins"),
+            version: String::from("0.1.0"),
+            state,
+        }
+    }
+
+    fn bridge() -> Bridge {
+        let (events, _) = broadcast::channel(BACKLOG);
+        let envelope = envelope("job", State::NotAvailable, SystemTime::now());
+        let mut jobs = HashMap::new();
+        jobs.insert(envelope.job_id.clone(), envelope);
+        Bridge {
+            jobs: Arc::new(Mutex::new(jobs)),
+            events,
+            redactor: redact::Redactor::new(),
+        }
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_known_jobs_only() {
+        let bridge = bridge();
+        let response = bridge.respond(get("/jobs/job"));
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let envelope: Envelope = serde_json::from_slice(&body).unwrap();
+        assert_eq!(envelope.job_id, "job");
+
+        let response = bridge.respond(get("/jobs/other"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = bridge.respond(get("/jobs/job/other"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn only_answers_get() {
+        let request = Request::post("/jobs").body(Body::empty()).unwrap();
+        let response = bridge().respond(request);
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn streams_events_from_last_state() {
+        let response = bridge().respond(get("/jobs/job/events"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+        let mut body = response.into_body();
+        let event = body.next().await.unwrap().unwrap();
+        let event = String::from_utf8(event.to_vec()).unwrap();
+        assert!(event.starts_with("event: state\ndata: {"));
+        assert!(event.ends_with("}\n\n"));
+    }
+
+    #[test]
+    fn forgets_jobs_over_for_long() {
+        let bridge = bridge();
+        let now = SystemTime::now();
+        let long_ago = now - RETENTION - Duration::from_secs(1);
+        bridge.record(&envelope("over", State::Cancelled, long_ago), now);
+        bridge.record(&envelope("recent", State::Cancelled, now), now);
+        bridge.record(&envelope("running", State::NotAvailable, long_ago), now);
+        let jobs = bridge.jobs.lock().unwrap();
+        let mut ids: Vec<&str> = jobs.keys().map(|id| id.as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["job", "recent", "running"]);
+    }
+}
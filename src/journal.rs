@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::driver::{Envelope, State};
+use super::elasticsearch;
+use super::error;
+
+// Each job writes its transitions to a journal, one JSON object per line, so that if the process
+// dies, a new driver can pick up the job where it was left. The journal is removed once the job
+// is over.
+
+pub const DIR: &str = "./work/journal";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Entry {
+    pub envelope: Envelope,
+    // Elasticsearch before indexing, so that a resumed job can still rollback
+    pub snapshot: Option<elasticsearch::Snapshot>,
+}
+
+// Whether a job left in that state has something left to do. A job which didn't start, or which
+// is over, has not.
+pub fn is_resumable(state: &State) -> bool {
+    !matches!(
+        state,
+        State::NotAvailable
+            | State::Available { .. }
+            | State::Failed { .. }
+            | State::Cancelled
+            | State::Failure(_)
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+// Held by the driver of a job while it drives, so that no one else resumes the job. The operating
+// system releases it if the driver dies.
+#[derive(Debug)]
+pub struct Lock {
+    _file: std::fs::File,
+}
+
+impl Journal {
+    pub fn new(dir: &Path, job_id: &str) -> Self {
+        Journal {
+            path: dir.join(format!("{}.jsonl", job_id)),
+        }
+    }
+
+    // We sync after each entry: an entry which isn't on disk is no use after a crash.
+    pub fn append(&self, entry: &Entry) -> Result<(), error::Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).context(error::IOError {
+                details: format!("Could not create directory {}", dir.display()),
+            })?;
+        }
+        let mut line = serde_json::to_string(entry).context(error::SerdeJSONError {
+            details: String::from("Could not serialize journal entry"),
+        })?;
+        line.push('\n');
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context(error::IOError {
+                details: format!("Could not open journal {}", self.path.display()),
+            })?;
+        file.write_all(line.as_bytes()).context(error::IOError {
+            details: format!("Could not write to journal {}", self.path.display()),
+        })?;
+        file.sync_data().context(error::IOError {
+            details: format!("Could not sync journal {}", self.path.display()),
+        })
+    }
+
+    // The last entry written in full. A crash may have left the last line incomplete, we skip it.
+    pub fn last(&self) -> Result<Option<Entry>, error::Error> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&self.path).context(error::IOError {
+            details: format!("Could not read journal {}", self.path.display()),
+        })?;
+        Ok(content
+            .lines()
+            .rev()
+            .find_map(|line| serde_json::from_str(line).ok()))
+    }
+
+    pub fn remove(&self) -> Result<(), error::Error> {
+        // The job is over, there is nothing left to resume, so its lock can go too
+        let lock = self.lock_path();
+        if lock.exists() {
+            std::fs::remove_file(&lock).context(error::IOError {
+                details: format!("Could not remove journal lock {}", lock.display()),
+            })?;
+        }
+        if !self.path.exists() {
+            return Ok(());
+        }
+        std::fs::remove_file(&self.path).context(error::IOError {
+            details: format!("Could not remove journal {}", self.path.display()),
+        })
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.path.with_extension("lock")
+    }
+
+    // None if another driver, maybe in another process, holds the lock.
+    pub fn lock(&self) -> Result<Option<Lock>, error::Error> {
+        let path = self.lock_path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context(error::IOError {
+                details: format!("Could not create directory {}", dir.display()),
+            })?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .context(error::IOError {
+                details: format!("Could not open journal lock {}", path.display()),
+            })?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Lock { _file: file })),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+            Err(std::fs::TryLockError::Error(err)) => Err(err).context(error::IOError {
+                details: format!("Could not lock journal {}", path.display()),
+            }),
+        }
+    }
+
+    // Whether a driver is on the job, without holding the lock for longer than it takes to know.
+    pub fn is_locked(&self) -> Result<bool, error::Error> {
+        if !self.lock_path().exists() {
+            return Ok(false);
+        }
+        Ok(self.lock()?.is_none())
+    }
+}
+
+// The last entry of each job which didn't get to the end, and which no driver is on.
+pub fn unfinished(dir: &Path) -> Result<Vec<Entry>, error::Error> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let entries = std::fs::read_dir(dir).context(error::IOError {
+        details: format!("Could not list journals in {}", dir.display()),
+    })?;
+    let mut unfinished = Vec::new();
+    for entry in entries {
+        let path = entry
+            .context(error::IOError {
+                details: format!("Could not list journals in {}", dir.display()),
+            })?
+            .path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("jsonl") {
+            continue;
+        }
+        let journal = Journal { path };
+        if journal.is_locked()? {
+            continue;
+        }
+        if let Some(entry) = journal.last()? {
+            if is_resumable(&entry.envelope.state) {
+                unfinished.push(entry);
+            }
+        }
+    }
+    // Oldest first
+    unfinished.sort_by_key(|entry| entry.envelope.timestamp);
+    Ok(unfinished)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn entry(job_id: &str) -> Entry {
+        Entry {
+            envelope: Envelope {
+                job_id: String::from(job_id),
+                sequence: 1,
+                timestamp: SystemTime::now(),
+                data_source: String::from("osm"),
+                region: String::from("fr"),
+                index_type: String::from("admins"),
+                version: String::from("0.1.0"),
+                state: State::DownloadingInProgress {
+                    started_at: SystemTime::now(),
+                    attempt: 1,
+                },
+            },
+            snapshot: None,
+        }
+    }
+
+    #[test]
+    fn unfinished_skips_jobs_a_driver_is_on() {
+        let dir = std::env::temp_dir().join(format!("journal-{}", std::process::id()));
+        let journal = Journal::new(&dir, "job");
+        journal.append(&entry("job")).unwrap();
+        assert_eq!(unfinished(&dir).unwrap().len(), 1);
+
+        let lock = journal.lock().unwrap();
+        assert!(lock.is_some());
+        assert!(journal.lock().unwrap().is_none());
+        assert!(unfinished(&dir).unwrap().is_empty());
+
+        drop(lock);
+        assert!(!journal.is_locked().unwrap());
+        assert_eq!(unfinished(&dir).unwrap().len(), 1);
+
+        journal.remove().unwrap();
+        assert!(unfinished(&dir).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
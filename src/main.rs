@@ -1,19 +1,86 @@
-use async_zmq::StreamExt;
-use clap::{App, Arg};
+// Without ZeroMQ, there is no server, and what only the server uses is left unused.
+#![cfg_attr(not(feature = "zmq"), allow(dead_code))]
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+#[cfg(feature = "zmq")]
+use futures::StreamExt;
 use snafu::ResultExt;
+use std::io::Write;
 
 mod bano;
+#[cfg(any(feature = "zmq", test))]
+mod client;
+mod control;
 mod cosmogony;
 mod download;
 mod driver;
+mod elasticsearch;
+mod endpoint;
 mod error;
+mod fsm;
+mod graph;
+mod history;
+#[cfg(any(feature = "zmq", test))]
+mod http;
+mod journal;
+mod notify;
 mod ntfs;
 mod osm;
+mod process;
+mod recovery;
+mod redact;
+mod scheduler;
+#[cfg(feature = "zmq")]
+mod server;
+mod timeout;
+#[cfg(any(feature = "zmq", test))]
+mod ui;
+mod webhook;
 
 #[tokio::main]
 async fn main() -> Result<(), error::Error> {
+    // Errors end up printed, so we mask what looks like a secret.
+    let code = run().await.map_err(|err| error::Error::MiscError {
+        details: redact::redact(&format!("{}", err)),
+    })?;
+    if code != EXIT_AVAILABLE {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+// How a job ended, for scripts running it. An error preventing the job from running at all
+// exits with 1.
+const EXIT_AVAILABLE: i32 = 0;
+const EXIT_FAILED: i32 = 2; // The job gave up, after it undid what it could
+const EXIT_CANCELLED: i32 = 3;
+const EXIT_FAILURE: i32 = 4; // The FSM received an event it did not expect
+const EXIT_INTERRUPTED: i32 = 5; // The job stopped before the end, eg while paused
+
+// The importers only take a connection string, so we refuse what they couldn't use, rather than
+// have every job fail.
+const UNSUPPORTED_BY_IMPORTERS: &str =
+    "the importers only take a connection string, with at most a username and password";
+
+fn exit_code(state: &driver::State) -> i32 {
+    match state {
+        driver::State::Available { .. } => EXIT_AVAILABLE,
+        driver::State::Failed { .. } => EXIT_FAILED,
+        driver::State::Cancelled => EXIT_CANCELLED,
+        driver::State::Failure(_) => EXIT_FAILURE,
+        _ => EXIT_INTERRUPTED,
+    }
+}
+
+// The exit code of the program, which is that of the job, if it runs one.
+async fn run() -> Result<i32, error::Error> {
+    // A mistake in the transition table is ours, we'd rather know before running anything.
+    fsm::validate(&fsm::table()).map_err(|problems| error::Error::MiscError {
+        details: format!("Invalid state machine: {}", problems.join(", ")),
+    })?;
+
     // Retrieve command line arguments
-    let matches = App::new("Create Elasticsearch Index")
+    let app = App::new("Create Elasticsearch Index")
         .version("0.1")
         .author("Matthieu Paindavoine")
         .arg(
@@ -34,8 +101,567 @@ async fn main() -> Result<(), error::Error> {
                 .value_name("STRING")
                 .help("region"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("es_url")
+                .global(true)
+                .long("es-url")
+                .value_name("URL")
+                .env("ELASTICSEARCH_URL")
+                .hide_env_values(true)
+                .default_value("http://localhost:9200")
+                .help("elasticsearch URL"),
+        )
+        .arg(
+            Arg::with_name("es_username")
+                .global(true)
+                .long("es-username")
+                .value_name("STRING")
+                .env("ELASTICSEARCH_USERNAME")
+                .help("elasticsearch username, for basic auth"),
+        )
+        .arg(
+            Arg::with_name("es_password")
+                .global(true)
+                .long("es-password")
+                .value_name("STRING")
+                .env("ELASTICSEARCH_PASSWORD")
+                .hide_env_values(true)
+                .help("elasticsearch password, for basic auth"),
+        )
+        .arg(
+            Arg::with_name("es_api_key")
+                .global(true)
+                .long("es-api-key")
+                .value_name("STRING")
+                .env("ELASTICSEARCH_API_KEY")
+                .hide_env_values(true)
+                .validator(|_| Err(String::from(UNSUPPORTED_BY_IMPORTERS)))
+                .help("not supported: the importers can't authenticate with an API key"),
+        )
+        .arg(
+            Arg::with_name("es_ca_cert")
+                .global(true)
+                .long("es-ca-cert")
+                .value_name("FILE")
+                .env("ELASTICSEARCH_CA_CERT")
+                .validator(|_| Err(String::from(UNSUPPORTED_BY_IMPORTERS)))
+                .help("not supported: the importers can't trust a CA certificate"),
+        )
+        .arg(
+            Arg::with_name("es_password_in_args")
+                .global(true)
+                .long("es-password-in-args")
+                .help("lets the importers get the elasticsearch password in their arguments"),
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .value_name("FILE")
+                .help("writes a summary of the job as JSON to that file, '-' for stdout"),
+        )
+        .arg(
+            Arg::with_name("retry")
+                .global(true)
+                .long("retry")
+                .value_name("PHASE=ATTEMPTS[:DELAY]")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "tries a phase (download, processing, indexing, validation) up to ATTEMPTS \
+                     times, DELAY seconds apart, eg indexing=3:60",
+                ),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .global(true)
+                .long("timeout")
+                .value_name("[SOURCE:]PHASE=SECONDS")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "stops a phase which runs longer than SECONDS, for all data sources, or for \
+                     SOURCE only, eg osm:indexing=7200",
+                ),
+        )
+        .arg(
+            Arg::with_name("notifier")
+                .global(true)
+                .long("notifier")
+                .value_name("NOTIFIER")
+                .default_value(notify::DEFAULT)
+                .help(
+                    "where states are published: zmq, stdout, file:PATH or unix:PATH, as JSON \
+                     lines but for zmq",
+                ),
+        )
+        .arg(
+            Arg::with_name("webhook")
+                .global(true)
+                .long("webhook")
+                .value_name("URL")
+                .multiple(true)
+                .number_of_values(1)
+                .help("posts the state of a job to that URL when the job is over"),
+        )
+        .arg(
+            Arg::with_name("webhook_on")
+                .global(true)
+                .long("webhook-on")
+                .value_name("STATE")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "states which trigger the webhooks, by default Available, Failed and Failure",
+                ),
+        )
+        .arg(
+            Arg::with_name("webhook_template")
+                .global(true)
+                .long("webhook-template")
+                .value_name("FILE")
+                .help("JSON posted to the webhooks, with {{job_id}}, {{state}}, {{details}}, ..."),
+        )
+        .arg(
+            Arg::with_name("webhook_attempts")
+                .global(true)
+                .long("webhook-attempts")
+                .value_name("COUNT")
+                .default_value("3")
+                .help("how many times we post to a webhook which fails"),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Lists past jobs, and how long their phases took")
+                .arg(
+                    Arg::with_name("data_source")
+                        .long("data-source")
+                        .value_name("STRING")
+                        .help("only jobs for that data source"),
+                )
+                .arg(
+                    Arg::with_name("region")
+                        .long("region")
+                        .value_name("STRING")
+                        .help("only jobs for that region"),
+                )
+                .arg(
+                    Arg::with_name("index_type")
+                        .long("index-type")
+                        .value_name("STRING")
+                        .help("only jobs for that index type"),
+                )
+                .arg(
+                    Arg::with_name("outcome")
+                        .long("outcome")
+                        .value_name("STATE")
+                        .help("only jobs which ended in that state, eg Available, Failed"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .short("n")
+                        .long("limit")
+                        .value_name("COUNT")
+                        .default_value("20")
+                        .help("how many of the most recent jobs are listed"),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .value_name("FILE")
+                        .default_value(history::PATH)
+                        .help("where the history is kept"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("graph")
+                .about("Prints the transitions of the state machine, as a graph")
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["dot", "mermaid"])
+                        .default_value("dot")
+                        .help("graphviz (dot) or mermaid"),
+                )
+                .arg(
+                    Arg::with_name("commands")
+                        .long("commands")
+                        .help("also draws the transitions of commands (pause, cancel)"),
+                )
+                .arg(
+                    Arg::with_name("job")
+                        .long("job")
+                        .value_name("JOB ID")
+                        .help("highlights the path taken by that job, from the history"),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .value_name("FILE")
+                        .default_value(history::PATH)
+                        .help("where the history is kept"),
+                ),
+        );
+
+    // Running a server, and following jobs, takes ZeroMQ.
+    #[cfg(feature = "zmq")]
+    let app = app
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Runs a server, which drives the jobs submitted to it")
+                .arg(
+                    Arg::with_name("port")
+                        .short("p")
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value("5555")
+                        .help("port on which states are published, requests go to the next one"),
+                )
+                .arg(
+                    Arg::with_name("queue")
+                        .long("queue")
+                        .value_name("FILE")
+                        .default_value("./work/queue.json")
+                        .help("where jobs waiting to run are kept, across restarts"),
+                )
+                .arg(
+                    Arg::with_name("max_jobs")
+                        .long("max-jobs")
+                        .value_name("COUNT")
+                        .default_value("4")
+                        .help("how many jobs run at the same time"),
+                )
+                .arg(
+                    Arg::with_name("max_source_jobs")
+                        .long("max-source-jobs")
+                        .value_name("SOURCE=COUNT")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("how many jobs run at the same time for a data source, eg osm=2"),
+                )
+                .arg(
+                    Arg::with_name("max_downloads")
+                        .long("max-downloads")
+                        .value_name("COUNT")
+                        .default_value("3")
+                        .help("how many jobs download at the same time"),
+                )
+                .arg(
+                    Arg::with_name("max_processing")
+                        .long("max-processing")
+                        .value_name("COUNT")
+                        .default_value("1")
+                        .help("how many jobs process (eg generate cosmogony) at the same time"),
+                )
+                .arg(
+                    Arg::with_name("max_indexing")
+                        .long("max-indexing")
+                        .value_name("COUNT")
+                        .help("how many jobs index at the same time, unlimited by default"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Prints the progress of the jobs of a running driver, or server")
+                .arg(
+                    Arg::with_name("endpoint")
+                        .long("endpoint")
+                        .value_name("ENDPOINT")
+                        .default_value("tcp://127.0.0.1:5555")
+                        .help("where the states are published"),
+                )
+                .arg(
+                    Arg::with_name("job")
+                        .long("job")
+                        .value_name("JOB ID")
+                        .help("only follows that job, until it is over"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("http")
+                .about("Serves the states of the jobs of a running driver, or server, over HTTP")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .value_name("ADDRESS")
+                        .default_value("127.0.0.1:8080")
+                        .help("address on which HTTP requests are accepted"),
+                )
+                .arg(
+                    Arg::with_name("endpoint")
+                        .long("endpoint")
+                        .value_name("ENDPOINT")
+                        .default_value("tcp://127.0.0.1:5555")
+                        .help("where the states are published"),
+                ),
+        );
+
+    let matches = app.get_matches();
+
+    match matches.subcommand() {
+        #[cfg(feature = "zmq")]
+        ("serve", Some(matches)) => serve(matches).await.map(|()| EXIT_AVAILABLE),
+        ("history", Some(matches)) => show_history(matches).map(|()| EXIT_AVAILABLE),
+        ("graph", Some(matches)) => show_graph(matches).map(|()| EXIT_AVAILABLE),
+        #[cfg(feature = "zmq")]
+        ("watch", Some(matches)) => watch(matches).await,
+        #[cfg(feature = "zmq")]
+        ("http", Some(matches)) => serve_http(matches).await.map(|()| EXIT_AVAILABLE),
+        _ => job(&matches).await,
+    }
+}
+
+#[cfg(feature = "zmq")]
+// Runs the server until it fails.
+async fn serve(matches: &ArgMatches<'_>) -> Result<(), error::Error> {
+    let es = connection(matches)?;
+    let port = matches.value_of("port").unwrap_or("5555");
+    let port = port.parse::<u32>().map_err(|err| error::Error::MiscError {
+        details: format!("Invalid port {}: {}", port, err),
+    })?;
+    let queue = scheduler::Queue::load(matches.value_of("queue").map(std::path::PathBuf::from))?;
+    let mut data_sources = std::collections::HashMap::new();
+    for limit in matches.values_of("max_source_jobs").into_iter().flatten() {
+        let mut parts = limit.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(data_source), Some(count)) => {
+                data_sources.insert(String::from(data_source), count_arg(count)?);
+            }
+            _ => {
+                return Err(error::Error::MiscError {
+                    details: format!("Invalid data source limit {}, expected SOURCE=COUNT", limit),
+                });
+            }
+        }
+    }
+    let limits = scheduler::Limits {
+        jobs: count_arg(matches.value_of("max_jobs").unwrap_or("4"))?,
+        data_sources,
+    };
+    let semaphore = |name| -> Result<Option<scheduler::Semaphore>, error::Error> {
+        match matches.value_of(name) {
+            Some(count) => Ok(Some(scheduler::Semaphore::new(count_arg(count)?))),
+            None => Ok(None),
+        }
+    };
+    let phases = scheduler::PhaseLimits {
+        download: semaphore("max_downloads")?,
+        processing: semaphore("max_processing")?,
+        indexing: semaphore("max_indexing")?,
+    };
+    println!(
+        "Publishing states on port {}, accepting requests on port {}",
+        port,
+        port + 1
+    );
+    let recovery = recovery_policy(matches)?;
+    let timeouts = timeouts(matches)?;
+    let webhooks = webhooks(matches)?;
+    let mut server = server::Server::new(
+        es,
+        String::from("state"),
+        port,
+        queue,
+        limits,
+        phases,
+        recovery,
+        timeouts,
+        &notifier(matches)?,
+    )?;
+    server.set_webhooks(webhooks);
+    server.serve().await
+}
+
+// Which phases are tried again after an error, from the command line.
+fn recovery_policy(matches: &ArgMatches<'_>) -> Result<recovery::Policy, error::Error> {
+    let mut recovery = recovery::Policy::default();
+    for retry in matches.values_of("retry").into_iter().flatten() {
+        recovery.parse(retry)?;
+    }
+    Ok(recovery)
+}
+
+// How long each phase may run, from the command line.
+fn timeouts(matches: &ArgMatches<'_>) -> Result<timeout::Timeouts, error::Error> {
+    let mut timeouts = timeout::Timeouts::default();
+    for spec in matches.values_of("timeout").into_iter().flatten() {
+        timeouts.parse(spec)?;
+    }
+    Ok(timeouts)
+}
+
+// Where states are published, from the command line.
+fn notifier(matches: &ArgMatches<'_>) -> Result<notify::Config, error::Error> {
+    notify::Config::parse(matches.value_of("notifier").unwrap_or(notify::DEFAULT))
+}
+
+// The webhooks, from the command line, which all share the same states, template and attempts.
+fn webhooks(matches: &ArgMatches<'_>) -> Result<Vec<webhook::Webhook>, error::Error> {
+    let states: Option<Vec<&str>> = matches
+        .values_of("webhook_on")
+        .map(|states| states.collect());
+    let template = match matches.value_of("webhook_template") {
+        Some(file) => Some(std::fs::read_to_string(file).context(error::IOError {
+            details: format!("Could not read webhook template {}", file),
+        })?),
+        None => None,
+    };
+    let attempts = matches.value_of("webhook_attempts").unwrap_or("3");
+    let attempts = attempts
+        .parse::<u32>()
+        .map_err(|err| error::Error::MiscError {
+            details: format!("Invalid webhook attempts {}: {}", attempts, err),
+        })?;
+    let mut webhooks = Vec::new();
+    for url in matches.values_of("webhook").into_iter().flatten() {
+        let mut webhook = webhook::Webhook::new(url)?.attempts(attempts);
+        if let Some(states) = &states {
+            webhook = webhook.states(states)?;
+        }
+        if let Some(template) = &template {
+            webhook = webhook.template(template.as_str())?;
+        }
+        webhooks.push(webhook);
+    }
+    Ok(webhooks)
+}
+
+// Lists the most recent jobs matching the filter, then statistics on all of them.
+fn show_history(matches: &ArgMatches<'_>) -> Result<(), error::Error> {
+    let filter = history::Filter {
+        data_source: matches.value_of("data_source").map(String::from),
+        region: matches.value_of("region").map(String::from),
+        index_type: matches.value_of("index_type").map(String::from),
+        outcome: matches.value_of("outcome").map(String::from),
+    };
+    let limit = count_arg(matches.value_of("limit").unwrap_or("20"))?;
+    let records: Vec<history::Record> =
+        history::History::new(matches.value_of("file").unwrap_or(history::PATH))
+            .records()?
+            .into_iter()
+            .filter(|record| filter.matches(record))
+            .collect();
+    if records.is_empty() {
+        println!("No job found");
+        return Ok(());
+    }
 
+    let duration = |duration: Option<std::time::Duration>| {
+        duration
+            .map(history::format_duration)
+            .unwrap_or_else(|| String::from("-"))
+    };
+    println!(
+        "{:<10} {:<48} {:<14} {:>9} {:>9} {:>9} {:>9}",
+        "finished", "job", "outcome", "download", "process", "index", "total"
+    );
+    for record in records.iter().rev().take(limit) {
+        println!(
+            "{:<10} {:<48} {:<14} {:>9} {:>9} {:>9} {:>9}",
+            record
+                .finished_at
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            record.job_id,
+            record.outcome,
+            duration(record.download),
+            duration(record.processing),
+            duration(record.indexing),
+            duration(Some(record.duration())),
+        );
+        for error in &record.errors {
+            println!("{:<10} {}", "", error);
+        }
+    }
+
+    println!();
+    println!(
+        "{:<10} {:>6} {:>9} {:>9} {:>9} {:>9}",
+        "phase", "jobs", "min", "mean", "median", "max"
+    );
+    type Measure = fn(&history::Record) -> Option<std::time::Duration>;
+    let phases: [(&str, Measure); 4] = [
+        ("download", |record| record.download),
+        ("process", |record| record.processing),
+        ("index", |record| record.indexing),
+        ("total", |record| Some(record.duration())),
+    ];
+    for (phase, duration) in phases.iter() {
+        let durations: Vec<std::time::Duration> = records.iter().filter_map(duration).collect();
+        if let Some(stats) = history::Statistics::new(&durations) {
+            println!(
+                "{:<10} {:>6} {:>9} {:>9} {:>9} {:>9}",
+                phase,
+                stats.count,
+                history::format_duration(stats.min),
+                history::format_duration(stats.mean),
+                history::format_duration(stats.median),
+                history::format_duration(stats.max),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn show_graph(matches: &ArgMatches<'_>) -> Result<(), error::Error> {
+    let format = match matches.value_of("format") {
+        Some("mermaid") => graph::Format::Mermaid,
+        _ => graph::Format::Dot,
+    };
+    let path = match matches.value_of("job") {
+        Some(job_id) => history::History::new(matches.value_of("file").unwrap_or(history::PATH))
+            .records()?
+            .into_iter()
+            .rev()
+            .find(|record| record.job_id == job_id)
+            .map(|record| record.path)
+            .ok_or(error::Error::MiscError {
+                details: format!("Job {} not found in history", job_id),
+            })?,
+        None => Vec::new(),
+    };
+    print!(
+        "{}",
+        graph::render(&fsm::table(), format, matches.is_present("commands"), &path)
+    );
+    Ok(())
+}
+
+// A limit, which must allow at least one job.
+fn count_arg(count: &str) -> Result<usize, error::Error> {
+    match count.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(error::Error::MiscError {
+            details: format!("Invalid count {}, expected a positive number", count),
+        }),
+    }
+}
+
+// How we connect to Elasticsearch, from the command line or the environment.
+fn connection(matches: &ArgMatches<'_>) -> Result<elasticsearch::Connection, error::Error> {
+    // We don't put the URL in the error, it may contain credentials
+    let es = matches
+        .value_of("es_url")
+        .unwrap_or("http://localhost:9200");
+    let es = url::Url::parse(es).context(error::URLError {
+        details: String::from("Could not parse Elasticsearch URL"),
+    })?;
+    let mut es = elasticsearch::Connection::new(es);
+    if let Some(username) = matches.value_of("es_username") {
+        es.auth = Some(elasticsearch::Auth::Basic {
+            username: String::from(username),
+            password: String::from(matches.value_of("es_password").unwrap_or("")),
+        });
+    }
+    es.password_in_args = matches.is_present("es_password_in_args");
+    es.check_importers()?;
+    Ok(es)
+}
+
+// Runs a single job, and follows its progress.
+async fn job(matches: &ArgMatches<'_>) -> Result<i32, error::Error> {
     let index_type = matches
         .value_of("index_type")
         .ok_or(error::Error::MiscError {
@@ -50,61 +676,392 @@ async fn main() -> Result<(), error::Error> {
         details: String::from("Missing Region"),
     })?;
 
+    let es = connection(matches)?;
+
+    let notifier = notifier(matches)?;
+
+    // With '--summary -', stdout only gets the summary, as JSON, so we don't print the progress.
+    let summary_to_stdout = matches.value_of("summary") == Some("-");
+    if summary_to_stdout && matches!(notifier, notify::Config::Stdout) {
+        return Err(error::Error::MiscError {
+            details: String::from("The summary and the states can't both go to stdout"),
+        });
+    }
+
     // Now construct and initialize the Finite State Machine (FSM)
     // state is the name of the topic we're asking the publisher to broadcast message,
     // 5555 is the port
-    let mut driver =
-        driver::Driver::new(index_type, data_source, region, String::from("state"), 5555)?;
-
-    // Ready a subscription connection to receive notifications from the FSM
-    let mut zmq = async_zmq::subscribe("tcp://127.0.0.1:5555")
-        .context(error::ZMQSocketError {
-            details: String::from("Could not subscribe on tcp://127.0.0.1:5555"),
-        })?
-        .connect()
-        .context(error::ZMQError {
-            details: String::from("Could not connect subscribe"),
-        })?;
-    zmq.set_subscribe("state")
-        .context(error::ZMQSubscribeError {
-            details: format!("Could not subscribe to '{}' topic", "state"),
-        })?;
+    let mut driver = driver::Driver::new(
+        index_type,
+        data_source,
+        region,
+        es,
+        String::from("state"),
+        &notifier,
+        5555,
+    )?;
 
-    // Start the FSM
-    let _ = tokio::spawn(async move { driver.drive().await })
-        .await
-        .context(error::TokioJoinError {
-            details: String::from("Could not run FSM to completion"),
-        })?;
+    driver.set_recovery(recovery_policy(matches)?);
+    driver.set_timeouts(timeouts(matches)?);
+    driver.set_webhooks(webhooks(matches)?);
 
-    // and listen for notifications
-    while let Some(msg) = zmq.next().await {
-        // Received message is a type of Result<MessageBuf>
-        let msg = msg.context(error::ZMQRecvError {
-            details: String::from("ZMQ Reception Error"),
-        })?;
+    // If the same job was interrupted, we pick it up where it was left.
+    let unfinished = journal::unfinished(std::path::Path::new(journal::DIR))?
+        .into_iter()
+        .find(|entry| {
+            entry.envelope.data_source == data_source
+                && entry.envelope.region == region
+                && entry.envelope.index_type == index_type
+        });
+    if let Some(entry) = unfinished {
+        eprintln!(
+            "Resuming job {} from {:?}",
+            entry.envelope.job_id, entry.envelope.state
+        );
+        driver.set_job_id(entry.envelope.job_id);
+    }
 
-        let msg = msg
-            .iter()
-            .skip(1) // skip the topic
-            .next()
-            .ok_or(error::Error::MiscError {
-                details: String::from("Just one item in a multipart message. That is plain wrong!"),
+    // We wait for our subscriber before starting, so that it sees every state. Only a ZeroMQ
+    // subscriber can follow the job, otherwise whoever reads the notifier does.
+    if notifier.is_zmq() && !summary_to_stdout {
+        driver.expect_subscribers(1);
+    }
+
+    // Start the FSM, it runs alongside the subscription
+    #[cfg(feature = "zmq")]
+    let job_id = String::from(driver.job_id());
+    let fsm = tokio::spawn(async move {
+        driver
+            .drive()
+            .await
+            .map(|()| (driver.state().clone(), driver.summary()))
+    });
+
+    // We follow the job until it is over, and tell the FSM it can start once we're subscribed.
+    // A driver which fails won't publish the end of the job, so we stop following it then.
+    #[cfg(feature = "zmq")]
+    let result = if notifier.is_zmq() && !summary_to_stdout {
+        let mut fsm = fsm;
+        let notifications = client::Watcher::new("tcp://127.0.0.1:5555", "state")
+            .job(job_id)
+            .synchronize_with("tcp://127.0.0.1:5556")
+            .watch();
+        let following = follow(notifications, interactive());
+        tokio::pin!(following);
+        tokio::select! {
+            result = &mut fsm => {
+                // The last states may still be on their way
+                if let Ok(Ok(_)) = &result {
+                    following.await;
+                }
+                result
+            }
+            _ = &mut following => fsm.await,
+        }
+    } else {
+        fsm.await
+    };
+    #[cfg(not(feature = "zmq"))]
+    let result = fsm.await;
+
+    let (state, summary) = result.context(error::TokioJoinError {
+        details: String::from("Could not run FSM to completion"),
+    })??;
+    if let Some(summary) = summary {
+        report_summary(
+            &summary,
+            matches.value_of("summary"),
+            &mut std::io::stdout(),
+            &mut std::io::stderr(),
+        )?;
+    }
+    Ok(exit_code(&state))
+}
+
+#[cfg(feature = "zmq")]
+// Follows the jobs of a driver, or of a server, until the job we watch is over.
+async fn watch(matches: &ArgMatches<'_>) -> Result<i32, error::Error> {
+    let endpoint = matches
+        .value_of("endpoint")
+        .unwrap_or("tcp://127.0.0.1:5555");
+    // We can only draw the progress of a single job.
+    let last = match matches.value_of("job") {
+        Some(job_id) => {
+            let notifications = client::Watcher::new(endpoint, "state").job(job_id).watch();
+            follow(notifications, interactive()).await
+        }
+        None => follow(client::watch(endpoint, "state"), false).await,
+    };
+    Ok(last.as_ref().map(exit_code).unwrap_or(EXIT_INTERRUPTED))
+}
+
+#[cfg(feature = "zmq")]
+// Runs the HTTP bridge until it fails.
+async fn serve_http(matches: &ArgMatches<'_>) -> Result<(), error::Error> {
+    let listen = matches.value_of("listen").unwrap_or("127.0.0.1:8080");
+    let address =
+        listen
+            .parse::<std::net::SocketAddr>()
+            .map_err(|err| error::Error::MiscError {
+                details: format!("Invalid address {}: {}", listen, err),
             })?;
-        println!("Received: {}", msg.as_str().unwrap());
-        let state = serde_json::from_str(msg.as_str().unwrap()).context(error::SerdeJSONError {
-            details: String::from("Could not deserialize state"),
-        })?;
+    let endpoint = matches
+        .value_of("endpoint")
+        .unwrap_or("tcp://127.0.0.1:5555");
+    println!(
+        "Serving the states published on {} on http://{}",
+        endpoint, address
+    );
+    http::serve(endpoint, "state", address).await
+}
 
-        match state {
-            driver::State::NotAvailable => {
-                break;
+#[cfg(feature = "zmq")]
+// Whether we can draw the progress of a job, rather than print a line per state.
+fn interactive() -> bool {
+    use std::io::IsTerminal;
+    std::io::stdout().is_terminal()
+        && std::env::var("TERM")
+            .map(|term| term != "dumb")
+            .unwrap_or(true)
+}
+
+#[cfg(feature = "zmq")]
+// Prints the notifications until there are no more, and returns the last state.
+async fn follow(
+    mut notifications: client::Notifications,
+    interactive: bool,
+) -> Option<driver::State> {
+    let mut screen = ui::Screen::new();
+    let mut last = None;
+    loop {
+        let notification = if interactive {
+            // Dropping the pending next() doesn't lose anything, the stream keeps it.
+            tokio::select! {
+                notification = notifications.next() => notification,
+                _ = tokio::time::delay_for(ui::TICK) => {
+                    let _ = screen.draw();
+                    continue;
+                }
+            }
+        } else {
+            notifications.next().await
+        };
+        match notification {
+            Some(Ok(notification)) => {
+                if interactive {
+                    screen.update(&notification);
+                    let _ = screen.draw();
+                } else {
+                    print_notification(&notification);
+                }
+                if let client::Notification::State { envelope, .. } = notification {
+                    last = Some(envelope.state);
+                }
             }
-            driver::State::Available => {
-                break;
+            // The client goes on after an error
+            Some(Err(err)) if interactive => screen.report(&format!("{}", err)),
+            Some(Err(err)) => println!("{}", err),
+            None => break,
+        }
+    }
+    last
+}
+
+#[cfg(feature = "zmq")]
+fn print_notification(notification: &client::Notification) {
+    match notification {
+        client::Notification::State { envelope, missed } => {
+            if *missed > 0 {
+                println!("{} missed {} state(s)", envelope.job_id, missed);
+            }
+            println!(
+                "{} #{} {}",
+                envelope.job_id,
+                envelope.sequence,
+                describe(&envelope.state)
+            );
+        }
+        client::Notification::Heartbeat(heartbeat) => {
+            let timeout = heartbeat
+                .timeout
+                .map(|timeout| format!(" of {}", history::format_duration(timeout)))
+                .unwrap_or_default();
+            println!(
+                "{} {:?} running for {}{}",
+                heartbeat.job_id,
+                heartbeat.phase,
+                history::format_duration(heartbeat.elapsed),
+                timeout
+            );
+        }
+    }
+}
+
+#[cfg(feature = "zmq")]
+// The state, and what matters about it, on one line.
+fn describe(state: &driver::State) -> String {
+    use driver::State;
+    let details = match state {
+        State::DownloadingInProgress { attempt, .. }
+        | State::ProcessingInProgress { attempt, .. }
+        | State::IndexingInProgress { attempt, .. }
+        | State::ValidationInProgress { attempt } => format!("attempt {}", attempt),
+        State::Downloaded {
+            file_path,
+            duration,
+        }
+        | State::Processed {
+            file_path,
+            duration,
+        } => format!(
+            "{} in {}",
+            file_path.display(),
+            history::format_duration(*duration)
+        ),
+        State::Indexed { duration } => format!("in {}", history::format_duration(*duration)),
+        State::WaitingForCluster {
+            details, attempts, ..
+        } => format!("check {}: {}", attempts, details),
+        State::DownloadingError { error, attempts }
+        | State::ProcessingError {
+            error, attempts, ..
+        }
+        | State::IndexingError {
+            error, attempts, ..
+        }
+        | State::ValidationError { error, attempts }
+        | State::Failed { error, attempts } => {
+            format!(
+                "{:?} after {} attempt(s): {}",
+                error.kind, attempts, error.details
+            )
+        }
+        State::RollbackInProgress { cause, .. } => cause.details.clone(),
+        State::RollbackError { details, .. } => details.clone(),
+        State::RolledBack { indices, .. } => format!("removed {}", indices.join(", ")),
+        State::Available { index, alias } => format!("{} serves {}", alias, index),
+        State::Paused { state } => format!("in {:?}", state.kind()),
+        State::Failure(details) => details.clone(),
+        State::NotAvailable | State::Cancelled => String::new(),
+    };
+    format!("{:?} {}", state.kind(), details)
+        .trim_end()
+        .to_string()
+}
+
+// The human summary goes to stdout, unless the summary as JSON does, so that it can be parsed.
+fn report_summary(
+    summary: &history::Record,
+    file: Option<&str>,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> Result<(), error::Error> {
+    let printed = if file == Some("-") {
+        print_summary(summary, err)
+    } else {
+        print_summary(summary, out)
+    };
+    printed.context(error::IOError {
+        details: String::from("Could not print job summary"),
+    })?;
+    match file {
+        Some(file) => write_summary(summary, file, out),
+        None => Ok(()),
+    }
+}
+
+fn print_summary(summary: &history::Record, out: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "Job {} ended in {} after {}",
+        summary.job_id,
+        summary.outcome,
+        history::format_duration(summary.duration())
+    )?;
+    let phases = [
+        ("download", summary.download, summary.download_size),
+        ("processing", summary.processing, summary.processed_size),
+        ("indexing", summary.indexing, None),
+    ];
+    for (phase, duration, size) in phases.iter() {
+        if let Some(duration) = duration {
+            match size {
+                Some(size) => writeln!(
+                    out,
+                    "  {:<10} {} ({} bytes)",
+                    phase,
+                    history::format_duration(*duration),
+                    size
+                )?,
+                None => writeln!(
+                    out,
+                    "  {:<10} {}",
+                    phase,
+                    history::format_duration(*duration)
+                )?,
             }
-            _ => {}
         }
     }
+    for error in &summary.errors {
+        writeln!(out, "  error: {}", error)?;
+    }
     Ok(())
 }
+
+fn write_summary(
+    summary: &history::Record,
+    file: &str,
+    out: &mut dyn Write,
+) -> Result<(), error::Error> {
+    let json = serde_json::to_string_pretty(summary).context(error::SerdeJSONError {
+        details: String::from("Could not serialize job summary"),
+    })?;
+    if file == "-" {
+        return writeln!(out, "{}", json).context(error::IOError {
+            details: String::from("Could not write job summary to stdout"),
+        });
+    }
+    std::fs::write(file, json).context(error::IOError {
+        details: format!("Could not write job summary to {}", file),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn summary_to_stdout_is_only_json() {
+        let summary = history::Record {
+            job_id: String::from("job"),
+            data_source: String::from("osm"),
+            region: String::from("fr"),
+            index_type: String::from("admins"),
+            started_at: SystemTime::UNIX_EPOCH,
+            finished_at: SystemTime::UNIX_EPOCH + Duration::from_secs(60),
+            download: Some(Duration::from_secs(60)),
+            download_size: Some(1024),
+            processing: None,
+            processed_size: None,
+            indexing: None,
+            outcome: String::from("Failed"),
+            errors: vec![String::from("Could not index")],
+            path: Vec::new(),
+        };
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        report_summary(&summary, Some("-"), &mut out, &mut err).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["job_id"], "job");
+        assert!(String::from_utf8(err)
+            .unwrap()
+            .starts_with("Job job ended in Failed"));
+
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        report_summary(&summary, None, &mut out, &mut err).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("Job job ended in Failed"));
+        assert!(err.is_empty());
+    }
+}
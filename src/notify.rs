@@ -0,0 +1,345 @@
+#[cfg(feature = "zmq")]
+use async_zmq::{Message, MultipartIter, SinkExt};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use snafu::ResultExt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::error;
+
+// Where drivers publish their states, heartbeats, and hellos: a message is a topic and a
+// payload, which is JSON except for the hellos.
+pub trait Notifier: Send + Sync {
+    fn notify<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+    ) -> BoxFuture<'a, Result<(), error::Error>>;
+}
+
+// A server shares its notifier between all its drivers.
+pub type Publisher = Arc<dyn Notifier>;
+
+// How long we wait for a slow client of a Unix socket before we drop it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Where states go unless told otherwise.
+#[cfg(feature = "zmq")]
+pub const DEFAULT: &str = "zmq";
+#[cfg(not(feature = "zmq"))]
+pub const DEFAULT: &str = "stdout";
+
+// Which notifier, from the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Config {
+    Zmq,           // A ZeroMQ PUB socket, on the port we are given
+    Stdout,        // JSON lines
+    File(PathBuf), // JSON lines, appended
+    Unix(PathBuf), // JSON lines, to each client connected to the socket
+}
+
+impl Config {
+    // 'zmq', 'stdout', 'file:PATH', or 'unix:PATH'
+    pub fn parse(spec: &str) -> Result<Self, error::Error> {
+        let mut parts = spec.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("zmq"), None) => Ok(Config::Zmq),
+            (Some("stdout"), None) => Ok(Config::Stdout),
+            (Some("file"), Some(path)) if !path.is_empty() => Ok(Config::File(PathBuf::from(path))),
+            (Some("unix"), Some(path)) if !path.is_empty() => Ok(Config::Unix(PathBuf::from(path))),
+            _ => Err(error::Error::MiscError {
+                details: format!(
+                    "Invalid notifier {}, expected zmq, stdout, file:PATH or unix:PATH",
+                    spec
+                ),
+            }),
+        }
+    }
+
+    // Only ZeroMQ subscribers can synchronize with a driver, and watch it.
+    pub fn is_zmq(&self) -> bool {
+        *self == Config::Zmq
+    }
+
+    pub fn open(&self, port: u32) -> Result<Publisher, error::Error> {
+        match self {
+            #[cfg(feature = "zmq")]
+            Config::Zmq => Ok(Arc::new(Zmq::bind(port)?)),
+            #[cfg(not(feature = "zmq"))]
+            Config::Zmq => Err(error::Error::MiscError {
+                details: format!(
+                    "Could not publish on port {}, built without ZeroMQ support",
+                    port
+                ),
+            }),
+            Config::Stdout => Ok(Arc::new(Stdout)),
+            Config::File(path) => Ok(Arc::new(Lines::open(path)?)),
+            Config::Unix(path) => Ok(Arc::new(Unix::bind(path)?)),
+        }
+    }
+}
+
+// What we write for each message, where there are no topics.
+#[derive(Debug, Serialize)]
+struct Line<'a> {
+    topic: &'a str,
+    payload: serde_json::Value, // The hellos are not JSON, so they end up as strings
+}
+
+fn line(topic: &str, payload: &str) -> Result<String, error::Error> {
+    let payload = serde_json::from_str(payload)
+        .unwrap_or_else(|_| serde_json::Value::String(String::from(payload)));
+    let mut line =
+        serde_json::to_string(&Line { topic, payload }).context(error::SerdeJSONError {
+            details: format!("Could not serialize message on topic '{}'", topic),
+        })?;
+    line.push('\n');
+    Ok(line)
+}
+
+#[cfg(feature = "zmq")]
+type Socket = async_zmq::publish::Publish<std::vec::IntoIter<Message>, Message>;
+
+#[cfg(feature = "zmq")]
+pub struct Zmq {
+    socket: tokio::sync::Mutex<Socket>,
+}
+
+#[cfg(feature = "zmq")]
+impl Zmq {
+    pub fn bind(port: u32) -> Result<Self, error::Error> {
+        let zmq_endpoint = format!("tcp://127.0.0.1:{}", port);
+        let socket = async_zmq::publish(&zmq_endpoint)
+            .context(error::ZMQSocketError {
+                details: format!("Could not publish on endpoint '{}'", zmq_endpoint),
+            })?
+            .bind()
+            .context(error::ZMQError {
+                details: String::from("Could not bind socket for publication"),
+            })?;
+        Ok(Zmq {
+            socket: tokio::sync::Mutex::new(socket),
+        })
+    }
+}
+
+#[cfg(feature = "zmq")]
+impl Notifier for Zmq {
+    fn notify<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+    ) -> BoxFuture<'a, Result<(), error::Error>> {
+        Box::pin(async move {
+            let msg = vec![topic, payload];
+            let msg: Vec<Message> = msg.into_iter().map(Message::from).collect();
+            let res: MultipartIter<_, _> = msg.into();
+            self.socket
+                .lock()
+                .await
+                .send(res)
+                .await
+                .context(error::ZMQSendError {
+                    details: format!("Could not publish on topic '{}'", topic),
+                })
+        })
+    }
+}
+
+pub struct Stdout;
+
+impl Notifier for Stdout {
+    fn notify<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+    ) -> BoxFuture<'a, Result<(), error::Error>> {
+        Box::pin(async move {
+            let line = line(topic, payload)?;
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            out.write_all(line.as_bytes())
+                .and_then(|()| out.flush())
+                .context(error::IOError {
+                    details: String::from("Could not write message to stdout"),
+                })
+        })
+    }
+}
+
+// A file which other processes can tail.
+pub struct Lines {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Lines {
+    pub fn open(path: &Path) -> Result<Self, error::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(error::IOError {
+                details: format!("Could not open {}", path.display()),
+            })?;
+        Ok(Lines {
+            path: PathBuf::from(path),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Notifier for Lines {
+    fn notify<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+    ) -> BoxFuture<'a, Result<(), error::Error>> {
+        Box::pin(async move {
+            let line = line(topic, payload)?;
+            let mut file = self.file.lock().map_err(|_| error::Error::MiscError {
+                details: format!("Could not lock {}", self.path.display()),
+            })?;
+            file.write_all(line.as_bytes()).context(error::IOError {
+                details: format!("Could not write message to {}", self.path.display()),
+            })
+        })
+    }
+}
+
+// Like a PUB socket, each client connected receives the messages published after it connected.
+pub struct Unix {
+    path: PathBuf,
+    clients: Arc<Mutex<Vec<UnixStream>>>, // Accepted on their own thread
+}
+
+impl Unix {
+    pub fn bind(path: &Path) -> Result<Self, error::Error> {
+        // A socket left by a previous run would prevent us from binding
+        if path.exists() {
+            std::fs::remove_file(path).context(error::IOError {
+                details: format!("Could not remove {}", path.display()),
+            })?;
+        }
+        let listener = UnixListener::bind(path).context(error::IOError {
+            details: format!("Could not bind {}", path.display()),
+        })?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let accepted = clients.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                // A client which doesn't read would otherwise block the driver
+                if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err() {
+                    continue;
+                }
+                if let Ok(mut clients) = accepted.lock() {
+                    clients.push(stream);
+                }
+            }
+        });
+        Ok(Unix {
+            path: PathBuf::from(path),
+            clients,
+        })
+    }
+}
+
+impl Notifier for Unix {
+    fn notify<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+    ) -> BoxFuture<'a, Result<(), error::Error>> {
+        Box::pin(async move {
+            let line = line(topic, payload)?;
+            let clients = self.clients.clone();
+            let path = self.path.clone();
+            // A slow client blocks a write until it times out, which must not block the runtime.
+            tokio::task::spawn_blocking(move || {
+                let mut clients = clients.lock().map_err(|_| error::Error::MiscError {
+                    details: format!("Could not lock the clients of {}", path.display()),
+                })?;
+                // We forget the clients which are gone, or too slow
+                clients.retain(|client| {
+                    let mut client: &UnixStream = client;
+                    client.write_all(line.as_bytes()).is_ok()
+                });
+                Ok(())
+            })
+            .await
+            .context(error::TokioJoinError {
+                details: format!("Could not notify the clients of {}", self.path.display()),
+            })?
+        })
+    }
+}
+
+// Keeps the messages, to test drivers without any transport.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct Memory {
+    messages: Mutex<Vec<(String, String)>>,
+}
+
+#[cfg(test)]
+impl Memory {
+    pub fn new() -> Self {
+        Memory::default()
+    }
+
+    // The messages published so far, as topics and payloads.
+    pub fn messages(&self) -> Vec<(String, String)> {
+        self.messages
+            .lock()
+            .map(|messages| messages.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+impl Notifier for Memory {
+    fn notify<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+    ) -> BoxFuture<'a, Result<(), error::Error>> {
+        Box::pin(async move {
+            let mut messages = self.messages.lock().map_err(|_| error::Error::MiscError {
+                details: String::from("Could not lock messages"),
+            })?;
+            messages.push((String::from(topic), String::from(payload)));
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[tokio::test(threaded_scheduler)]
+    async fn unix_clients_get_each_message() {
+        let path = std::env::temp_dir().join(format!("notify-{}.sock", std::process::id()));
+        let unix = Unix::bind(&path).unwrap();
+        let client = UnixStream::connect(&path).unwrap();
+        // The client is accepted on its own thread
+        while unix.clients.lock().unwrap().is_empty() {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        unix.notify("state", r#"{"sequence": 0}"#).await.unwrap();
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"topic\":\"state\",\"payload\":{\"sequence\":0}}\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,10 +2,13 @@ use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use std::path::PathBuf;
 use std::process::Command;
-use url::Url;
 
+use super::control;
 use super::download;
+use super::elasticsearch;
 use super::error;
+use super::process;
+use super::redact;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct NTFSDownload {
@@ -45,7 +48,12 @@ struct NTFSDataset {
 // * has a hard coded timeout to 300s
 // It will create a directory 'osm' inside the working directory (if not already present)
 // It will download a file
-pub fn download_ntfs_region(working_dir: PathBuf, region: &str) -> Result<PathBuf, error::Error> {
+pub fn download_ntfs_region(
+    working_dir: PathBuf,
+    region: &str,
+    cancellation: &control::Cancellation,
+    progress: &control::Progress,
+) -> Result<PathBuf, error::Error> {
     // For NTFS, the download is a bit more involved.
     // We need to download a first file, which describe the available datasets.
     // So we download the file in json format, and use serde to get a list of datasets.
@@ -67,7 +75,7 @@ pub fn download_ntfs_region(working_dir: PathBuf, region: &str) -> Result<PathBu
             ),
         })?;
     }
-    let res = download::download(&target, filepath.clone())?;
+    let res = download::download(&target, filepath.clone(), cancellation, progress)?;
     let datasets = std::fs::read_to_string(&res.0).context(error::IOError {
         details: format!(
             "Could not read content of NTFS first download {}",
@@ -98,33 +106,30 @@ pub fn download_ntfs_region(working_dir: PathBuf, region: &str) -> Result<PathBu
     std::fs::remove_file(res.0.as_path()).context(error::IOError {
         details: format!("Could not remove {}", res.0.display()),
     })?;
-    let res = download::download(&url, filepath.clone())?;
+    let res = download::download(&url, filepath.clone(), cancellation, progress)?;
     let mut command = Command::new("unzip");
     // We want to unzip in the director 'filepath'
     command.arg("-d").arg(filepath.clone());
     // We want to overwrite files without prompting
     command.arg("-o");
     command.arg(res.0.as_path());
-    let output = command.output().context(error::IOError {
+    let output = process::output(&mut command, cancellation).context(error::IOError {
         details: format!("Could not unzip {}", filepath.display()),
     })?;
     // Same thing, we don't need the zip file, so remove it.
     std::fs::remove_file(res.0.as_path()).context(error::IOError {
         details: format!("Could not remove {}", res.0.display()),
     })?;
-    if !output.status.success() {
-        Err(error::Error::MiscError {
-            details: format!("=> {}", String::from_utf8(output.stderr).unwrap()),
-        })
-    } else {
-        Ok(filepath)
-    }
+    process::check("unzip", &output, &redact::Redactor::new())?;
+    Ok(filepath)
 }
 
 pub fn index_ntfs_region(
     mimirs_dir: PathBuf,
-    es: Url,
+    es: &elasticsearch::Connection,
+    dataset: &str,
     filepath: PathBuf,
+    cancellation: &control::Cancellation,
 ) -> Result<(), error::Error> {
     let mut execpath = mimirs_dir;
     execpath.push("target");
@@ -132,23 +137,19 @@ pub fn index_ntfs_region(
     execpath.push("ntfs2mimir");
     // FIXME Need test file exists
     let mut command = Command::new(&execpath);
+    es.configure(&mut command);
     command
-        .arg("--connection-string")
-        .arg(es.as_str())
+        .arg("--dataset")
+        .arg(dataset)
         .arg("--input")
         .arg(filepath.clone());
-    println!("command: {:?}", command);
-    let output = command.output().context(error::IOError {
+    eprintln!("command: {}", es.redact(&format!("{:?}", command)));
+    let output = process::output(&mut command, cancellation).context(error::IOError {
         details: format!(
             "Could not create ntfs2mimir command using {}",
             execpath.display()
         ),
     })?;
-    if !output.status.success() {
-        Err(error::Error::MiscError {
-            details: format!("=> {}", String::from_utf8(output.stderr).unwrap()),
-        })
-    } else {
-        Ok(())
-    }
+    process::check("ntfs2mimir", &output, &es.redactor())?;
+    Ok(())
 }
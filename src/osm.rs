@@ -1,10 +1,12 @@
 use snafu::ResultExt;
 use std::path::PathBuf;
 use std::process::Command;
-use url::Url;
 
+use super::control;
 use super::download;
+use super::elasticsearch;
 use super::error;
+use super::process;
 
 // Download the pbf associated with a region.
 // This is a very rudimentary function, which:
@@ -12,7 +14,12 @@ use super::error;
 // * has a hard coded timeout to 300s
 // It will create a directory 'osm' inside the working directory (if not already present)
 // It will download a file
-pub fn download_osm_region(working_dir: PathBuf, region: &str) -> Result<PathBuf, error::Error> {
+pub fn download_osm_region(
+    working_dir: PathBuf,
+    region: &str,
+    cancellation: &control::Cancellation,
+    progress: &control::Progress,
+) -> Result<PathBuf, error::Error> {
     let filename = format!("{}-latest.osm.pbf", region);
     let target = format!("https://download.geofabrik.de/europe/france/{}", filename);
     let mut filepath = working_dir;
@@ -25,18 +32,21 @@ pub fn download_osm_region(working_dir: PathBuf, region: &str) -> Result<PathBuf
             ),
         })?;
     }
-    let res = download::download(&target, filepath)?;
+    let res = download::download(&target, filepath, cancellation, progress)?;
     Ok(res.0)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn index_osm_region(
     mimirs_dir: PathBuf,
-    es: Url,
+    es: &elasticsearch::Connection,
+    dataset: &str,
     filepath: PathBuf, // osm pbf
     admin: bool,
     way: bool,
     poi: bool,
     city_level: u32,
+    cancellation: &control::Cancellation,
 ) -> Result<(), error::Error> {
     let mut execpath = mimirs_dir;
     execpath.push("target");
@@ -44,9 +54,10 @@ pub fn index_osm_region(
     execpath.push("osm2mimir");
     // FIXME Need test file exists
     let mut command = Command::new(&execpath);
+    es.configure(&mut command);
     command
-        .arg("--connection-string")
-        .arg(es.as_str())
+        .arg("--dataset")
+        .arg(dataset)
         .arg("--input")
         .arg(filepath.clone());
     if way {
@@ -59,18 +70,13 @@ pub fn index_osm_region(
         command.arg("--import-poi");
     }
     command.arg("--city-level").arg(city_level.to_string());
-    println!("command: {:?}", command);
-    let output = command.output().context(error::IOError {
+    eprintln!("command: {}", es.redact(&format!("{:?}", command)));
+    let output = process::output(&mut command, cancellation).context(error::IOError {
         details: format!(
             "Could not create osm2mimir command using {}",
             execpath.display()
         ),
     })?;
-    if !output.status.success() {
-        Err(error::Error::MiscError {
-            details: format!("=> {}", String::from_utf8(output.stderr).unwrap()),
-        })
-    } else {
-        Ok(())
-    }
+    process::check("osm2mimir", &output, &es.redactor())?;
+    Ok(())
 }
@@ -0,0 +1,140 @@
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+
+use super::control;
+use super::error;
+use super::redact;
+
+// How often we check if the command was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How many lines of stderr we keep when a command fails.
+const STDERR_LINES: usize = 20;
+
+// Like Command::output, but kills the child process if the job is cancelled, in which case it
+// returns an error of kind Interrupted.
+pub fn output(
+    command: &mut Command,
+    cancellation: &control::Cancellation,
+) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // We read the outputs in their own threads, otherwise the child could block on a full pipe.
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if cancellation.is_cancelled() {
+            child.kill()?;
+            child.wait()?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Command cancelled",
+            ));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.map(collect).unwrap_or_default(),
+        stderr: stderr.map(collect).unwrap_or_default(),
+    })
+}
+
+// An error if the command didn't succeed, with the end of what it printed on stderr, redacted.
+pub fn check(
+    program: &str,
+    output: &Output,
+    redactor: &redact::Redactor,
+) -> Result<(), error::Error> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    let tail = lines[lines.len().saturating_sub(STDERR_LINES)..].join("\n");
+    let details = match output.status.code() {
+        Some(code) => format!("{} exited with code {}", program, code),
+        None => format!("{} was killed by a signal", program),
+    };
+    Err(error::Error::SubprocessError {
+        details,
+        code: output.status.code(),
+        stderr: redactor.redact(&tail),
+    })
+}
+
+fn drain<R: Read + Send + 'static>(mut reader: R) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = reader.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+fn collect(handle: std::thread::JoinHandle<Vec<u8>>) -> Vec<u8> {
+    handle.join().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn output(status: i32, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(status),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn success_is_no_error() {
+        let redactor = redact::Redactor::new();
+        assert!(check("bano2mimir", &output(0, "warning"), &redactor).is_ok());
+    }
+
+    #[test]
+    fn keeps_the_tail_of_stderr_redacted() {
+        let mut redactor = redact::Redactor::new();
+        redactor.add_secret("hunter2");
+        let lines: Vec<String> = (1..=30).map(|i| format!("line {}", i)).collect();
+        let stderr = format!("{}\npassword hunter2", lines.join("\n"));
+        // Exit code 2, in the format of wait()
+        match check("osm2mimir", &output(2 << 8, &stderr), &redactor) {
+            Err(error::Error::SubprocessError {
+                details,
+                code,
+                stderr,
+            }) => {
+                assert_eq!(details, "osm2mimir exited with code 2");
+                assert_eq!(code, Some(2));
+                let tail: Vec<&str> = stderr.lines().collect();
+                assert_eq!(tail.len(), STDERR_LINES);
+                assert_eq!(tail[0], "line 12");
+                assert_eq!(tail[STDERR_LINES - 1], "password ********");
+            }
+            other => panic!("Expected a subprocess error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn killed_by_a_signal() {
+        let redactor = redact::Redactor::new();
+        match check("cosmogony", &output(9, ""), &redactor) {
+            Err(error::Error::SubprocessError { details, code, .. }) => {
+                assert_eq!(details, "cosmogony was killed by a signal");
+                assert_eq!(code, None);
+            }
+            other => panic!("Expected a subprocess error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use super::driver::Phase;
+use super::error;
+
+// After an error, a phase can be tried again, instead of giving up on the job. By default, each
+// phase is tried once.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    pub attempts: u32,   // How many times the phase is tried, at most
+    pub delay: Duration, // How long we wait before trying again
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 1,
+            delay: Duration::from_secs(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    pub download: Retry,
+    pub processing: Retry,
+    pub indexing: Retry,
+    pub validation: Retry,
+}
+
+impl Policy {
+    pub fn get(&self, phase: Phase) -> Retry {
+        match phase {
+            Phase::Download => self.download,
+            Phase::Processing => self.processing,
+            Phase::Indexing => self.indexing,
+            Phase::Validation => self.validation,
+        }
+    }
+
+    pub fn set(&mut self, phase: Phase, retry: Retry) {
+        match phase {
+            Phase::Download => self.download = retry,
+            Phase::Processing => self.processing = retry,
+            Phase::Indexing => self.indexing = retry,
+            Phase::Validation => self.validation = retry,
+        }
+    }
+
+    // Whether a phase which failed after that many attempts should be tried again.
+    pub fn should_retry(&self, phase: Phase, attempts: u32) -> bool {
+        attempts < self.get(phase).attempts
+    }
+
+    // Reads a retry from the command line, eg 'indexing=3:60' for 3 attempts, 60s apart. The
+    // delay is optional.
+    pub fn parse(&mut self, spec: &str) -> Result<(), error::Error> {
+        let invalid = || error::Error::MiscError {
+            details: format!(
+                "Invalid retry {}, expected PHASE=ATTEMPTS[:DELAY], eg indexing=3:60",
+                spec
+            ),
+        };
+        let mut parts = spec.splitn(2, '=');
+        let phase = parts.next().and_then(Phase::parse).ok_or_else(invalid)?;
+        let mut values = parts.next().ok_or_else(invalid)?.splitn(2, ':');
+        let attempts = values
+            .next()
+            .and_then(|attempts| attempts.parse::<u32>().ok())
+            .filter(|attempts| *attempts > 0)
+            .ok_or_else(invalid)?;
+        let delay = match values.next() {
+            Some(delay) => Duration::from_secs(delay.parse::<u64>().map_err(|_| invalid())?),
+            None => Duration::from_secs(0),
+        };
+        self.set(phase, Retry { attempts, delay });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_attempts_and_delay() {
+        let mut policy = Policy::default();
+        policy.parse("indexing=3:60").unwrap();
+        policy.parse("Download=2").unwrap();
+        assert_eq!(
+            policy.get(Phase::Indexing),
+            Retry {
+                attempts: 3,
+                delay: Duration::from_secs(60)
+            }
+        );
+        assert_eq!(
+            policy.get(Phase::Download),
+            Retry {
+                attempts: 2,
+                delay: Duration::from_secs(0)
+            }
+        );
+        assert_eq!(policy.get(Phase::Processing), Retry::default());
+        assert!(policy.should_retry(Phase::Indexing, 2));
+        assert!(!policy.should_retry(Phase::Indexing, 3));
+    }
+
+    #[test]
+    fn rejects_invalid_retries() {
+        let mut policy = Policy::default();
+        for spec in &[
+            "",
+            "indexing",
+            "indexing=",
+            "indexing=0",
+            "indexing=-1",
+            "indexing=3:",
+            "indexing=3:soon",
+            "sleeping=3",
+        ] {
+            assert!(policy.parse(spec).is_err(), "{} should be invalid", spec);
+        }
+        assert_eq!(policy, Policy::default());
+    }
+}
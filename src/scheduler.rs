@@ -0,0 +1,374 @@
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use super::control;
+use super::driver::Phase;
+use super::error;
+
+// The server doesn't run the jobs as soon as they are submitted: they wait in a queue until the
+// limits allow them to run. Within a job, each phase may also have to wait for its turn.
+
+// How often a phase waiting for its turn checks if the job was cancelled.
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub data_source: String,
+    pub region: String,
+    pub index_type: String,
+    pub priority: i32, // Higher runs first
+    pub submitted_at: SystemTime,
+}
+
+impl Job {
+    // Two jobs doing the same thing
+    fn same_as(&self, other: &Job) -> bool {
+        self.data_source == other.data_source
+            && self.region == other.region
+            && self.index_type == other.index_type
+    }
+}
+
+// The pending jobs, saved to disk after each change, so they survive a restart of the server.
+#[derive(Debug)]
+pub struct Queue {
+    path: Option<PathBuf>,
+    pending: Vec<Job>,
+}
+
+impl Queue {
+    // Without a path, the queue is not persisted.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, error::Error> {
+        let pending = match path {
+            Some(ref path) if path.exists() => {
+                let content = std::fs::read_to_string(path).context(error::IOError {
+                    details: format!("Could not read job queue {}", path.display()),
+                })?;
+                serde_json::from_str(&content).context(error::SerdeJSONError {
+                    details: format!("Could not deserialize job queue {}", path.display()),
+                })?
+            }
+            _ => Vec::new(),
+        };
+        Ok(Queue { path, pending })
+    }
+
+    // Writes the queue as it will be after a change, which we only make once it is on disk, so
+    // that memory and disk agree when the write fails.
+    fn save(&self, pending: &[Job]) -> Result<(), error::Error> {
+        if let Some(ref path) = self.path {
+            let content = serde_json::to_string_pretty(pending).context(error::SerdeJSONError {
+                details: String::from("Could not serialize job queue"),
+            })?;
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).context(error::IOError {
+                    details: format!("Could not create directory {}", dir.display()),
+                })?;
+            }
+            // We write to a temporary file first, so that a crash leaves the queue intact.
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, content).context(error::IOError {
+                details: format!("Could not write job queue {}", tmp.display()),
+            })?;
+            std::fs::rename(&tmp, path).context(error::IOError {
+                details: format!("Could not replace job queue {}", path.display()),
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn pending(&self) -> &[Job] {
+        &self.pending
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Job> {
+        self.pending.iter().find(|job| job.id == id)
+    }
+
+    // Returns the id of the job in the queue, which is the id of an identical pending job if
+    // there is one.
+    pub fn push(&mut self, job: Job) -> Result<String, error::Error> {
+        let mut pending = self.pending.clone();
+        let id = match pending.iter_mut().find(|pending| pending.same_as(&job)) {
+            // The job was submitted again, maybe because it's more urgent now.
+            Some(same) if job.priority > same.priority => {
+                same.priority = job.priority;
+                same.id.clone()
+            }
+            Some(same) => return Ok(same.id.clone()),
+            None => {
+                let id = job.id.clone();
+                pending.push(job);
+                id
+            }
+        };
+        self.save(&pending)?;
+        self.pending = pending;
+        Ok(id)
+    }
+
+    // Removes the highest priority job which can run, the oldest one among equals.
+    pub fn pop<F: Fn(&Job) -> bool>(&mut self, can_run: F) -> Result<Option<Job>, error::Error> {
+        let next = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, job)| can_run(job))
+            .max_by(|(_, a), (_, b)| {
+                a.priority
+                    .cmp(&b.priority)
+                    .then_with(|| b.submitted_at.cmp(&a.submitted_at))
+            })
+            .map(|(i, _)| i);
+        match next {
+            Some(i) => {
+                let mut pending = self.pending.clone();
+                let job = pending.remove(i);
+                self.save(&pending)?;
+                self.pending = pending;
+                Ok(Some(job))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<bool, error::Error> {
+        let mut pending = self.pending.clone();
+        pending.retain(|job| job.id != id);
+        if pending.len() == self.pending.len() {
+            return Ok(false);
+        }
+        self.save(&pending)?;
+        self.pending = pending;
+        Ok(true)
+    }
+}
+
+// How many jobs run at the same time, overall and per data source.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub jobs: usize,
+    pub data_sources: HashMap<String, usize>, // Data sources not listed are only bound by jobs
+}
+
+impl Limits {
+    // running maps the running jobs to their description. A job never runs next to the same job,
+    // since both would write, and roll back, the indices of the same alias.
+    pub fn allows(&self, job: &Job, running: &HashMap<String, Job>) -> bool {
+        if running.len() >= self.jobs || running.values().any(|other| other.same_as(job)) {
+            return false;
+        }
+        match self.data_sources.get(&job.data_source) {
+            Some(limit) => {
+                running
+                    .values()
+                    .filter(|other| other.data_source == job.data_source)
+                    .count()
+                    < *limit
+            }
+            None => true,
+        }
+    }
+}
+
+// A blocking semaphore, since the phases run in a blocking context.
+#[derive(Debug)]
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        *self.semaphore.lock() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    // A count is never left half updated, so a job which panicked holding the lock is no reason
+    // to stop counting, let alone to stop limiting.
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.permits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Waits for a permit, unless the job is cancelled.
+    fn acquire(&self, cancellation: &control::Cancellation) -> Option<Permit<'_>> {
+        let mut permits = self.lock();
+        while *permits == 0 {
+            if cancellation.is_cancelled() {
+                return None;
+            }
+            permits = match self.released.wait_timeout(permits, WAIT_INTERVAL) {
+                Ok((permits, _)) => permits,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        *permits -= 1;
+        Some(Permit { semaphore: self })
+    }
+}
+
+// How many jobs can be in a given phase at the same time, shared by all the drivers of a server.
+#[derive(Debug, Default)]
+pub struct PhaseLimits {
+    pub download: Option<Semaphore>,
+    pub processing: Option<Semaphore>,
+    pub indexing: Option<Semaphore>,
+}
+
+impl PhaseLimits {
+    // Returns None if the phase is not limited, or if the job was cancelled while waiting.
+    pub fn acquire(
+        &self,
+        phase: Phase,
+        cancellation: &control::Cancellation,
+    ) -> Option<Permit<'_>> {
+        let semaphore = match phase {
+            Phase::Download => self.download.as_ref(),
+            Phase::Processing => self.processing.as_ref(),
+            Phase::Indexing => self.indexing.as_ref(),
+            Phase::Validation => None,
+        };
+        semaphore.and_then(|semaphore| semaphore.acquire(cancellation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn job(id: &str, data_source: &str, priority: i32, submitted_at: u64) -> Job {
+        Job {
+            id: String::from(id),
+            data_source: String::from(data_source),
+            region: String::from("fr"),
+            index_type: String::from("admins"),
+            priority,
+            submitted_at: SystemTime::UNIX_EPOCH + Duration::from_secs(submitted_at),
+        }
+    }
+
+    #[test]
+    fn push_keeps_one_of_the_same_job() {
+        let mut queue = Queue::load(None).unwrap();
+        assert_eq!(queue.push(job("a", "osm", 0, 1)).unwrap(), "a");
+        assert_eq!(queue.push(job("b", "osm", 0, 2)).unwrap(), "a");
+        assert_eq!(queue.pending().len(), 1);
+        // Submitted again with a higher priority, the pending job gets it
+        assert_eq!(queue.push(job("c", "osm", 5, 3)).unwrap(), "a");
+        assert_eq!(queue.get("a").unwrap().priority, 5);
+        assert_eq!(queue.push(job("d", "bano", 0, 4)).unwrap(), "d");
+        assert_eq!(queue.pending().len(), 2);
+    }
+
+    #[test]
+    fn pop_highest_priority_then_oldest() {
+        let mut queue = Queue::load(None).unwrap();
+        queue.push(job("old", "osm", 0, 1)).unwrap();
+        queue.push(job("urgent", "bano", 1, 3)).unwrap();
+        queue.push(job("new", "cosmogony", 0, 2)).unwrap();
+        let pop = |queue: &mut Queue| queue.pop(|_| true).unwrap().map(|job| job.id);
+        assert_eq!(pop(&mut queue).as_deref(), Some("urgent"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("old"));
+        assert_eq!(pop(&mut queue).as_deref(), Some("new"));
+        assert_eq!(pop(&mut queue), None);
+    }
+
+    #[test]
+    fn pop_skips_jobs_which_cannot_run() {
+        let mut queue = Queue::load(None).unwrap();
+        queue.push(job("a", "osm", 1, 1)).unwrap();
+        queue.push(job("b", "bano", 0, 2)).unwrap();
+        let next = queue.pop(|job| job.data_source != "osm").unwrap();
+        assert_eq!(next.map(|job| job.id).as_deref(), Some("b"));
+        assert_eq!(queue.pending().len(), 1);
+    }
+
+    #[test]
+    fn keeps_jobs_when_the_queue_cannot_be_saved() {
+        let dir = std::env::temp_dir().join(format!("queue-{}", std::process::id()));
+        let path = dir.join("queue.json");
+        let mut queue = Queue::load(Some(path.clone())).unwrap();
+        queue.push(job("a", "osm", 0, 1)).unwrap();
+        // The temporary file can't be written over a directory
+        std::fs::create_dir_all(path.with_extension("tmp")).unwrap();
+        assert!(queue.push(job("b", "bano", 0, 2)).is_err());
+        assert!(queue.pop(|_| true).is_err());
+        assert!(queue.remove("a").is_err());
+        let ids: Vec<&str> = queue.pending().iter().map(|job| job.id.as_str()).collect();
+        assert_eq!(ids, vec!["a"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn limits_jobs_overall_and_per_data_source() {
+        let mut data_sources = HashMap::new();
+        data_sources.insert(String::from("osm"), 1);
+        let limits = Limits {
+            jobs: 2,
+            data_sources,
+        };
+        let mut running = HashMap::new();
+        assert!(limits.allows(&job("a", "osm", 0, 1), &running));
+        running.insert(String::from("a"), job("a", "osm", 0, 1));
+        assert!(!limits.allows(&job("b", "osm", 0, 2), &running));
+        assert!(limits.allows(&job("c", "bano", 0, 3), &running));
+        running.insert(String::from("c"), job("c", "bano", 0, 3));
+        assert!(!limits.allows(&job("d", "bano", 0, 4), &running));
+    }
+
+    #[test]
+    fn limits_one_job_per_alias() {
+        let limits = Limits {
+            jobs: 10,
+            data_sources: HashMap::new(),
+        };
+        let mut running = HashMap::new();
+        running.insert(String::from("a"), job("a", "osm", 0, 1));
+        assert!(!limits.allows(&job("b", "osm", 0, 2), &running));
+        let mut other_region = job("c", "osm", 0, 3);
+        other_region.region = String::from("nord");
+        assert!(limits.allows(&other_region, &running));
+    }
+
+    #[test]
+    fn semaphore_limits_after_a_panic() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let poisoner = semaphore.clone();
+        let _ = std::thread::spawn(move || {
+            let _permits = poisoner.permits.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(semaphore.permits.is_poisoned());
+
+        let cancellation = control::Cancellation::new();
+        let permit = semaphore.acquire(&cancellation);
+        assert!(permit.is_some());
+        // The only permit is taken, so we wait until cancelled
+        cancellation.cancel();
+        assert!(semaphore.acquire(&cancellation).is_none());
+        drop(permit);
+        assert!(semaphore.acquire(&cancellation).is_some());
+    }
+}
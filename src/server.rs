@@ -0,0 +1,283 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+
+use super::control;
+use super::driver;
+use super::elasticsearch;
+use super::endpoint;
+use super::error;
+use super::journal;
+use super::notify;
+use super::recovery;
+use super::scheduler;
+use super::timeout;
+use super::webhook;
+
+// A long running server, which runs a driver for each job submitted. All the drivers publish
+// their states on the same socket, and clients talk to the server on the next port.
+// Submitted jobs are queued, and start when the limits allow it.
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Request {
+    Submit {
+        data_source: String,
+        region: String,
+        index_type: String,
+        #[serde(default)]
+        priority: i32, // Higher runs first
+    },
+    Jobs,           // The current state of every job
+    Queue,          // The jobs waiting to run
+    Status(String), // Current state and history of a job
+    Cancel(String),
+    Pause(String),
+    Resume(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Response {
+    Submitted(String), // The id of the job, or of the identical job already queued
+    Jobs(Vec<driver::Envelope>),
+    Queue(Vec<scheduler::Job>),
+    Queued(scheduler::Job), // The job is still waiting to run
+    Status(Box<endpoint::Status>),
+    Accepted,
+    Error(String),
+}
+
+pub struct Server {
+    es: elasticsearch::Connection,
+    topic: String,
+    publish: driver::Publisher,
+    socket: endpoint::Socket,
+    jobs: HashMap<String, endpoint::Handle>, // Running jobs, forgotten once finished
+    queue: scheduler::Queue,
+    limits: scheduler::Limits,
+    running: HashMap<String, scheduler::Job>, // Running jobs
+    phases: Arc<scheduler::PhaseLimits>,
+    recovery: recovery::Policy,                 // Given to each driver
+    timeouts: timeout::Timeouts,                // Given to each driver
+    webhooks: Vec<webhook::Webhook>,            // Given to each driver
+    finished_tx: mpsc::UnboundedSender<String>, // Drivers tell us when they are done
+    finished: mpsc::UnboundedReceiver<String>,
+}
+
+impl Server {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        es: elasticsearch::Connection,
+        topic: String,
+        port: u32,
+        queue: scheduler::Queue,
+        limits: scheduler::Limits,
+        phases: scheduler::PhaseLimits,
+        recovery: recovery::Policy,
+        timeouts: timeout::Timeouts,
+        notifier: &notify::Config,
+    ) -> Result<Self, error::Error> {
+        let publish = notifier.open(port)?;
+        let socket = endpoint::bind(&format!("tcp://127.0.0.1:{}", port + 1))?;
+        let (finished_tx, finished) = mpsc::unbounded_channel();
+        Ok(Server {
+            es,
+            topic,
+            publish,
+            socket,
+            jobs: HashMap::new(),
+            queue,
+            limits,
+            running: HashMap::new(),
+            phases: Arc::new(phases),
+            recovery,
+            timeouts,
+            webhooks: Vec::new(),
+            finished_tx,
+            finished,
+        })
+    }
+
+    pub fn set_webhooks(&mut self, webhooks: Vec<webhook::Webhook>) {
+        self.webhooks = webhooks;
+    }
+
+    fn submit(
+        &mut self,
+        data_source: String,
+        region: String,
+        index_type: String,
+        priority: i32,
+    ) -> Result<Response, error::Error> {
+        let job = scheduler::Job {
+            id: driver::new_job_id(&data_source, &region, &index_type),
+            data_source,
+            region,
+            index_type,
+            priority,
+            submitted_at: SystemTime::now(),
+        };
+        let job_id = self.queue.push(job)?;
+        self.dispatch()?;
+        Ok(Response::Submitted(job_id))
+    }
+
+    // Starts as many queued jobs as the limits allow.
+    fn dispatch(&mut self) -> Result<(), error::Error> {
+        loop {
+            let limits = &self.limits;
+            let running = &self.running;
+            match self.queue.pop(|job| limits.allows(job, running))? {
+                Some(job) => self.start(job),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn start(&mut self, job: scheduler::Job) {
+        let (mut driver, handle) = driver::Driver::with_publisher(
+            job.index_type.clone(),
+            job.data_source.clone(),
+            job.region.clone(),
+            self.es.clone(),
+            self.topic.clone(),
+            self.publish.clone(),
+        );
+        driver.set_job_id(job.id.as_str());
+        driver.set_phase_limits(self.phases.clone());
+        driver.set_recovery(self.recovery.clone());
+        driver.set_timeouts(self.timeouts.clone());
+        driver.set_webhooks(self.webhooks.clone());
+        self.jobs.insert(job.id.clone(), handle);
+        let job_id = job.id.clone();
+        self.running.insert(job.id.clone(), job);
+        let finished = self.finished_tx.clone();
+        let redactor = self.es.redactor();
+        tokio::spawn(async move {
+            if let Err(err) = driver.drive().await {
+                eprintln!(
+                    "Job {} failed: {}",
+                    job_id,
+                    redactor.redact(&format!("{}", err))
+                );
+            }
+            let _ = finished.send(job_id);
+        });
+    }
+
+    fn command(
+        &mut self,
+        job_id: &str,
+        command: control::Command,
+    ) -> Result<Response, error::Error> {
+        if self.queue.get(job_id).is_some() {
+            // A job which hasn't started yet is simply forgotten
+            return match command {
+                control::Command::Cancel => {
+                    self.queue.remove(job_id)?;
+                    Ok(Response::Accepted)
+                }
+                _ => Ok(Response::Error(format!("Job {} has not started", job_id))),
+            };
+        }
+        let response = match self.jobs.get(job_id) {
+            Some(handle) => match handle.command(command) {
+                endpoint::Response::Accepted => Response::Accepted,
+                endpoint::Response::Error(details) => Response::Error(details),
+                response => Response::Error(format!("Unexpected response {:?}", response)),
+            },
+            None => Response::Error(format!("Unknown job {}", job_id)),
+        };
+        Ok(response)
+    }
+
+    fn respond(&mut self, request: Request) -> Result<Response, error::Error> {
+        let response = match request {
+            Request::Submit {
+                data_source,
+                region,
+                index_type,
+                priority,
+            } => self.submit(data_source, region, index_type, priority)?,
+            Request::Jobs => Response::Jobs(
+                self.jobs
+                    .values()
+                    .filter_map(|handle| handle.status().and_then(|status| status.current))
+                    .collect(),
+            ),
+            Request::Queue => Response::Queue(self.queue.pending().to_vec()),
+            Request::Status(job_id) => match self.jobs.get(&job_id) {
+                Some(handle) => match handle.status() {
+                    Some(status) => Response::Status(Box::new(status)),
+                    None => Response::Error(String::from("Status unavailable")),
+                },
+                None => match self.queue.get(&job_id) {
+                    Some(job) => Response::Queued(job.clone()),
+                    None => Response::Error(format!("Unknown job {}", job_id)),
+                },
+            },
+            Request::Cancel(job_id) => self.command(&job_id, control::Command::Cancel)?,
+            Request::Pause(job_id) => self.command(&job_id, control::Command::Pause)?,
+            Request::Resume(job_id) => self.command(&job_id, control::Command::Resume)?,
+        };
+        Ok(response)
+    }
+
+    // Runs until the journals can't be read. A request we can't receive or answer, or a queue we
+    // can't save, only concerns one request, so we go on.
+    pub async fn serve(mut self) -> Result<(), error::Error> {
+        // Jobs interrupted by the end of a previous run are queued again, and resume where they
+        // were left. Jobs left in the queue start right away.
+        for entry in journal::unfinished(Path::new(journal::DIR))? {
+            let envelope = entry.envelope;
+            println!("Resuming job {} from {:?}", envelope.job_id, envelope.state);
+            self.queue.push(scheduler::Job {
+                id: envelope.job_id,
+                data_source: envelope.data_source,
+                region: envelope.region,
+                index_type: envelope.index_type,
+                priority: 0,
+                submitted_at: envelope.timestamp,
+            })?;
+        }
+        self.dispatch_logged();
+        let redactor = self.es.redactor();
+        loop {
+            tokio::select! {
+                request = endpoint::recv_request(&self.socket) => match request {
+                    Ok(request) => {
+                        let response = match request {
+                            Ok(request) => self.respond(request).unwrap_or_else(|err| {
+                                Response::Error(format!("{}", err))
+                            }),
+                            Err(details) => Response::Error(details),
+                        };
+                        if let Err(err) = endpoint::send_response(&self.socket, &response).await {
+                            eprintln!("{}", redactor.redact(&format!("{}", err)));
+                        }
+                    }
+                    // There is no one to answer, we wait for the next request
+                    Err(err) => eprintln!("{}", redactor.redact(&format!("{}", err))),
+                },
+                Some(job_id) = self.finished.recv() => {
+                    // Its history was recorded by the driver
+                    self.running.remove(&job_id);
+                    self.jobs.remove(&job_id);
+                    self.dispatch_logged();
+                }
+            }
+        }
+    }
+
+    // A queue we can't save is no reason to stop serving the jobs.
+    fn dispatch_logged(&mut self) {
+        if let Err(err) = self.dispatch() {
+            eprintln!(
+                "Could not start queued jobs: {}",
+                self.es.redactor().redact(&format!("{}", err))
+            );
+        }
+    }
+}
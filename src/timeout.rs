@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::driver::Phase;
+use super::error;
+
+// How long a phase may run before the watchdog stops it, so that a hung importer or a stalled
+// download doesn't keep a job in progress forever. A timeout for a data source takes precedence
+// over the timeout for all of them. By default, phases don't time out.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timeouts {
+    phases: HashMap<Phase, Duration>,
+    data_sources: HashMap<(String, Phase), Duration>,
+}
+
+impl Timeouts {
+    pub fn get(&self, data_source: &str, phase: Phase) -> Option<Duration> {
+        self.data_sources
+            .get(&(data_source.to_lowercase(), phase))
+            .or_else(|| self.phases.get(&phase))
+            .cloned()
+    }
+
+    pub fn set(&mut self, data_source: Option<&str>, phase: Phase, timeout: Duration) {
+        match data_source {
+            Some(data_source) => {
+                self.data_sources
+                    .insert((data_source.to_lowercase(), phase), timeout);
+            }
+            None => {
+                self.phases.insert(phase, timeout);
+            }
+        }
+    }
+
+    // Reads a timeout from the command line, eg 'indexing=3600' for all the data sources, or
+    // 'osm:indexing=7200' for OSM only.
+    pub fn parse(&mut self, spec: &str) -> Result<(), error::Error> {
+        let invalid = || error::Error::MiscError {
+            details: format!(
+                "Invalid timeout {}, expected [SOURCE:]PHASE=SECONDS, eg osm:indexing=7200",
+                spec
+            ),
+        };
+        let mut parts = spec.splitn(2, '=');
+        let mut names = parts.next().ok_or_else(invalid)?.rsplitn(2, ':');
+        let phase = names.next().and_then(Phase::parse).ok_or_else(invalid)?;
+        let data_source = names.next();
+        let timeout = parts
+            .next()
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(invalid)?;
+        self.set(data_source, phase, timeout);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_source_takes_precedence() {
+        let mut timeouts = Timeouts::default();
+        timeouts.parse("indexing=3600").unwrap();
+        timeouts.parse("OSM:indexing=7200").unwrap();
+        assert_eq!(
+            timeouts.get("osm", Phase::Indexing),
+            Some(Duration::from_secs(7200))
+        );
+        assert_eq!(
+            timeouts.get("bano", Phase::Indexing),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(timeouts.get("osm", Phase::Download), None);
+    }
+
+    #[test]
+    fn rejects_invalid_timeouts() {
+        let mut timeouts = Timeouts::default();
+        for spec in &[
+            "",
+            "indexing",
+            "indexing=",
+            "indexing=0",
+            "indexing=soon",
+            "sleeping=60",
+            "osm:sleeping=60",
+        ] {
+            assert!(timeouts.parse(spec).is_err(), "{} should be invalid", spec);
+        }
+        assert_eq!(timeouts, Timeouts::default());
+    }
+}
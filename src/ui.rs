@@ -0,0 +1,352 @@
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::client::Notification;
+use super::control::Amount;
+use super::driver::{Phase, State};
+use super::history::format_duration;
+
+// Draws the progress of a job in the terminal, a line per phase, which we draw again over the
+// previous lines as states and heartbeats come in, and as time goes by.
+
+// How often we draw, to animate the spinner and the elapsed time.
+pub const TICK: Duration = Duration::from_millis(100);
+
+// Longer lines would wrap, and we'd draw over the wrong lines.
+const WIDTH: usize = 80;
+
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+const PHASES: [(Phase, &str); 4] = [
+    (Phase::Download, "download"),
+    (Phase::Processing, "process"),
+    (Phase::Indexing, "index"),
+    (Phase::Validation, "validate"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    Pending,
+    Running {
+        since: Instant,
+        attempt: u32,
+        amount: Option<Amount>, // From the last heartbeat
+        reported: Instant,      // When the last heartbeat came
+        rate: f64,              // Bytes per second between the last two heartbeats
+    },
+    Done(Option<Duration>),
+    Failed(String),
+    Skipped, // eg processing, which only cosmogony needs
+}
+
+pub struct Screen {
+    job_id: String,
+    phases: [Status; 4], // In the order of PHASES
+    message: String,     // What doesn't fit in a phase: paused, waiting for the cluster, ...
+    frame: usize,        // Of the spinner
+    drawn: bool,         // Whether there are lines to draw over
+}
+
+// When the phase started, from when the driver says it did.
+fn since(started_at: &SystemTime) -> Instant {
+    let elapsed = started_at.elapsed().unwrap_or_default();
+    Instant::now()
+        .checked_sub(elapsed)
+        .unwrap_or_else(Instant::now)
+}
+
+fn index(phase: Phase) -> usize {
+    PHASES
+        .iter()
+        .position(|(p, _)| *p == phase)
+        .unwrap_or_default()
+}
+
+// eg 12.3 MB
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Heartbeats are seconds apart, so in between we guess how far along the work is, at the rate it
+// was going, without going past the total.
+fn interpolate(amount: &Amount, rate: f64, reported: Instant) -> Amount {
+    let guess = amount.done + (rate * reported.elapsed().as_secs_f64()) as u64;
+    Amount {
+        done: match amount.total {
+            Some(total) if total > 0 => guess.min(total.max(amount.done)),
+            _ => guess,
+        },
+        total: amount.total,
+    }
+}
+
+fn format_amount(amount: &Amount) -> String {
+    match amount.total {
+        Some(total) if total > 0 => format!(
+            "{} / {} ({}%)",
+            format_bytes(amount.done),
+            format_bytes(total),
+            amount.done.min(total) * 100 / total
+        ),
+        _ => format_bytes(amount.done),
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen {
+            job_id: String::new(),
+            phases: [
+                Status::Pending,
+                Status::Pending,
+                Status::Pending,
+                Status::Pending,
+            ],
+            message: String::new(),
+            frame: 0,
+            drawn: false,
+        }
+    }
+
+    // A phase starts, so the phases before it which didn't run won't.
+    fn start(&mut self, phase: Phase, since: Instant, attempt: u32) {
+        let i = index(phase);
+        for status in self.phases[..i].iter_mut() {
+            if *status == Status::Pending {
+                *status = Status::Skipped;
+            }
+        }
+        self.phases[i] = Status::Running {
+            since,
+            attempt,
+            amount: None,
+            reported: Instant::now(),
+            rate: 0.0,
+        };
+        self.message.clear();
+    }
+
+    pub fn update(&mut self, notification: &Notification) {
+        match notification {
+            Notification::State { envelope, .. } => {
+                self.job_id = envelope.job_id.clone();
+                self.update_state(&envelope.state);
+            }
+            Notification::Heartbeat(heartbeat) => {
+                // The driver knows better how long the phase has been running
+                if let Status::Running {
+                    since,
+                    amount,
+                    reported,
+                    rate,
+                    ..
+                } = &mut self.phases[index(heartbeat.phase)]
+                {
+                    *since = Instant::now()
+                        .checked_sub(heartbeat.elapsed)
+                        .unwrap_or_else(Instant::now);
+                    *rate = match (&amount, &heartbeat.progress) {
+                        (Some(before), Some(now)) if now.done >= before.done => {
+                            let secs = reported.elapsed().as_secs_f64();
+                            if secs > 0.0 {
+                                (now.done - before.done) as f64 / secs
+                            } else {
+                                *rate
+                            }
+                        }
+                        _ => 0.0,
+                    };
+                    *amount = heartbeat.progress;
+                    *reported = Instant::now();
+                }
+            }
+        }
+    }
+
+    fn update_state(&mut self, state: &State) {
+        match state {
+            State::NotAvailable => {}
+            State::DownloadingInProgress {
+                started_at,
+                attempt,
+            } => self.start(Phase::Download, since(started_at), *attempt),
+            State::ProcessingInProgress {
+                started_at,
+                attempt,
+                ..
+            } => self.start(Phase::Processing, since(started_at), *attempt),
+            State::IndexingInProgress {
+                started_at,
+                attempt,
+                ..
+            } => self.start(Phase::Indexing, since(started_at), *attempt),
+            State::ValidationInProgress { attempt } => {
+                self.start(Phase::Validation, Instant::now(), *attempt)
+            }
+            State::Downloaded { duration, .. } => {
+                self.phases[index(Phase::Download)] = Status::Done(Some(*duration));
+            }
+            State::Processed { duration, .. } => {
+                self.phases[index(Phase::Processing)] = Status::Done(Some(*duration));
+            }
+            State::Indexed { duration } => {
+                self.phases[index(Phase::Indexing)] = Status::Done(Some(*duration));
+            }
+            State::Available {
+                index: served,
+                alias,
+            } => {
+                self.phases[index(Phase::Validation)] = Status::Done(None);
+                self.message = format!("{} serves {}", alias, served);
+            }
+            State::DownloadingError { error, .. }
+            | State::ProcessingError { error, .. }
+            | State::IndexingError { error, .. }
+            | State::ValidationError { error, .. } => {
+                self.phases[index(error.phase)] = Status::Failed(error.details.clone());
+            }
+            State::WaitingForCluster { attempts, .. } => {
+                self.message = format!("Waiting for Elasticsearch (check {})", attempts);
+            }
+            State::RollbackInProgress { .. } => {
+                self.message = String::from("Rolling back...");
+            }
+            State::RollbackError { details, .. } => {
+                self.message = format!("Could not roll back: {}", details);
+            }
+            State::RolledBack { .. } => {
+                self.message = String::from("Rolled back");
+            }
+            State::Failed { error, attempts } => {
+                self.message = format!("Failed after {} attempt(s)", attempts);
+                self.phases[index(error.phase)] = Status::Failed(error.details.clone());
+            }
+            State::Paused { .. } => {
+                self.message = String::from("Paused");
+            }
+            State::Cancelled => {
+                self.stop("cancelled");
+                self.message = String::from("Cancelled");
+            }
+            State::Failure(details) => {
+                self.stop("stopped");
+                self.message = details.clone();
+            }
+        }
+    }
+
+    // Shows an error which is not about the job, eg we lost the connection.
+    pub fn report(&mut self, details: &str) {
+        self.message = String::from(details);
+    }
+
+    // The job is over, whatever was running.
+    fn stop(&mut self, reason: &str) {
+        for status in self.phases.iter_mut() {
+            if let Status::Running { .. } = status {
+                *status = Status::Failed(String::from(reason));
+            }
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let spinner = SPINNER[self.frame % SPINNER.len()];
+        let mut lines = vec![format!("Job {}", self.job_id)];
+        for ((_, name), status) in PHASES.iter().zip(self.phases.iter()) {
+            let line = match status {
+                Status::Pending => format!("  · {}", name),
+                Status::Skipped => format!("  - {:<9} skipped", name),
+                Status::Running {
+                    since,
+                    attempt,
+                    amount,
+                    reported,
+                    rate,
+                } => {
+                    let mut line = format!(
+                        "  {} {:<9} {}",
+                        spinner,
+                        name,
+                        format_duration(since.elapsed())
+                    );
+                    if let Some(amount) = amount {
+                        let amount = interpolate(amount, *rate, *reported);
+                        line.push_str(&format!("  {}", format_amount(&amount)));
+                    }
+                    if *attempt > 1 {
+                        line.push_str(&format!("  (attempt {})", attempt));
+                    }
+                    line
+                }
+                Status::Done(Some(duration)) => {
+                    format!("  ✓ {:<9} {}", name, format_duration(*duration))
+                }
+                Status::Done(None) => format!("  ✓ {}", name),
+                Status::Failed(details) => format!("  ✗ {:<9} {}", name, details),
+            };
+            lines.push(line);
+        }
+        lines.push(format!("  {}", self.message));
+        lines
+    }
+
+    // Draws the job over what we drew before, which takes as many lines.
+    pub fn draw(&mut self) -> std::io::Result<()> {
+        let lines = self.lines();
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        if self.drawn {
+            write!(out, "\x1b[{}A", lines.len())?;
+        }
+        for line in lines {
+            // Clear the line, in case the new one is shorter
+            let line: String = line.chars().take(WIDTH).collect();
+            writeln!(out, "\x1b[2K{}", line)?;
+        }
+        out.flush()?;
+        self.frame += 1;
+        self.drawn = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_up_to_the_total() {
+        let reported = Instant::now() - Duration::from_secs(2);
+        let amount = Amount {
+            done: 1000,
+            total: Some(5000),
+        };
+        let guess = interpolate(&amount, 1000.0, reported);
+        assert!(guess.done >= 3000 && guess.done < 5000);
+        assert_eq!(interpolate(&amount, 10_000.0, reported).done, 5000);
+        assert_eq!(interpolate(&amount, 0.0, reported).done, 1000);
+
+        let unknown = Amount {
+            done: 1000,
+            total: None,
+        };
+        assert!(interpolate(&unknown, 10_000.0, reported).done >= 21_000);
+    }
+}
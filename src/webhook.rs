@@ -0,0 +1,383 @@
+use snafu::ResultExt;
+use std::time::Duration;
+
+use super::driver::{Envelope, State};
+use super::error;
+use super::fsm::StateKind;
+
+// Posts the state of a job to a URL when the job reaches some states, eg for a chat bot to say
+// that an index is available, or that a job failed.
+
+// How long a receiver has to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The states which trigger a webhook, unless told otherwise.
+const DEFAULT_STATES: [StateKind; 3] =
+    [StateKind::Available, StateKind::Failed, StateKind::Failure];
+
+// The string values of a template refer to the job with {{job_id}}, {{sequence}}, {{state}},
+// {{data_source}}, {{region}}, {{index_type}} and {{details}}, eg {"text": "{{job_id}} failed"}.
+// A value which is only {{envelope}} is replaced by the envelope, eg {"job": "{{envelope}}"}.
+const ENVELOPE: &str = "{{envelope}}";
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: url::Url,
+    states: Vec<StateKind>,
+    template: Option<serde_json::Value>, // The envelope is posted as is without one
+    attempts: u32,                       // How many times we post, at most
+    delay: Duration,                     // Before we post again, doubled after each attempt
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Self, error::Error> {
+        let url = url::Url::parse(url).context(error::URLError {
+            details: String::from("Could not parse webhook URL"),
+        })?;
+        Ok(Webhook {
+            url,
+            states: DEFAULT_STATES.to_vec(),
+            template: None,
+            attempts: 3,
+            delay: Duration::from_secs(1),
+        })
+    }
+
+    // State names, eg Available, compared case insensitively.
+    pub fn states(mut self, names: &[&str]) -> Result<Self, error::Error> {
+        let mut states = Vec::new();
+        for name in names {
+            let state = StateKind::ALL
+                .iter()
+                .find(|state| format!("{:?}", state).eq_ignore_ascii_case(name))
+                .ok_or_else(|| error::Error::MiscError {
+                    details: format!("Unknown state {} for webhook", name),
+                })?;
+            states.push(*state);
+        }
+        self.states = states;
+        Ok(self)
+    }
+
+    // The template must be JSON, and the envelope can only be a value of its own.
+    pub fn template(mut self, template: &str) -> Result<Self, error::Error> {
+        let template = serde_json::from_str(template).context(error::SerdeJSONError {
+            details: String::from("Webhook template is not valid JSON"),
+        })?;
+        if misplaces_envelope(&template) {
+            return Err(error::Error::MiscError {
+                details: format!(
+                    "Webhook template can only use {} as a whole string",
+                    ENVELOPE
+                ),
+            });
+        }
+        self.template = Some(template);
+        Ok(self)
+    }
+
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    // How we refer to the webhook in messages, since the URL itself may be a secret.
+    pub fn name(&self) -> String {
+        format!(
+            "webhook on {}",
+            self.url.host_str().unwrap_or_else(|| self.url.as_str())
+        )
+    }
+
+    pub fn triggers(&self, state: &State) -> bool {
+        self.states.contains(&state.kind())
+    }
+
+    // What we post, from an envelope whose state is already redacted.
+    pub fn body(&self, envelope: &Envelope) -> Result<String, error::Error> {
+        let template = match &self.template {
+            Some(template) => template,
+            None => {
+                return serde_json::to_string(envelope).context(error::SerdeJSONError {
+                    details: String::from("Could not serialize state for webhook"),
+                })
+            }
+        };
+        let values = [
+            ("job_id", envelope.job_id.clone()),
+            ("sequence", envelope.sequence.to_string()),
+            ("state", format!("{:?}", envelope.state.kind())),
+            ("data_source", envelope.data_source.clone()),
+            ("region", envelope.region.clone()),
+            ("index_type", envelope.index_type.clone()),
+            ("details", details(&envelope.state)),
+        ];
+        let json = serde_json::to_value(envelope).context(error::SerdeJSONError {
+            details: String::from("Could not serialize state for webhook"),
+        })?;
+        Ok(fill(template, &values, &json).to_string())
+    }
+
+    // Posts the body, and posts it again if the receiver fails, or can't be reached.
+    pub fn deliver(&self, body: &str) -> Result<(), error::Error> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context(error::ReqwestError {
+                details: String::from("Could not build HTTP client for webhook"),
+            })?;
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            match self.post(&client, body) {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt >= self.attempts => return Err(err),
+                Err(_) => {
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    fn post(&self, client: &reqwest::blocking::Client, body: &str) -> Result<(), error::Error> {
+        let resp = client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(String::from(body))
+            .send()
+            .context(error::ReqwestError {
+                details: format!("Could not post to {}", self.name()),
+            })?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(error::Error::MiscError {
+                details: format!("{} replied with status {}", self.name(), resp.status()),
+            })
+        }
+    }
+}
+
+fn misplaces_envelope(template: &serde_json::Value) -> bool {
+    match template {
+        serde_json::Value::String(text) => text != ENVELOPE && text.contains(ENVELOPE),
+        serde_json::Value::Array(values) => values.iter().any(misplaces_envelope),
+        serde_json::Value::Object(map) => map.values().any(misplaces_envelope),
+        _ => false,
+    }
+}
+
+// The template with its placeholders replaced. We only walk the template, so a value which looks
+// like a placeholder is left as is.
+fn fill(
+    template: &serde_json::Value,
+    values: &[(&str, String)],
+    envelope: &serde_json::Value,
+) -> serde_json::Value {
+    match template {
+        serde_json::Value::String(text) if text == ENVELOPE => envelope.clone(),
+        serde_json::Value::String(text) => serde_json::Value::String(substitute(text, values)),
+        serde_json::Value::Array(items) => serde_json::Value::Array(
+            items
+                .iter()
+                .map(|item| fill(item, values, envelope))
+                .collect(),
+        ),
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.iter()
+                .map(|(key, item)| (key.clone(), fill(item, values, envelope)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+// Replaces each {{name}} in one pass. Unknown names are left as they are.
+fn substitute(text: &str, values: &[(&str, String)]) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &after[..end])
+                .map(|(_, value)| (value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                result.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                result.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+// A sentence on what the state means, for those who read the notification.
+fn details(state: &State) -> String {
+    match state {
+        State::Available { index, alias } => format!("{} serves {}", alias, index),
+        State::Failed { error, attempts } => {
+            format!("{} (after {} attempt(s))", error.details, attempts)
+        }
+        State::DownloadingError { error, .. }
+        | State::ProcessingError { error, .. }
+        | State::IndexingError { error, .. }
+        | State::ValidationError { error, .. } => error.details.clone(),
+        State::RollbackError { details, .. } => details.clone(),
+        State::Failure(details) => details.clone(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::SystemTime;
+
+    fn envelope(state: State) -> Envelope {
+        Envelope {
+            job_id: String::from("job-{{region}}"),
+            sequence: 3,
+            timestamp: SystemTime::now(),
+            data_source: String::from("osm"),
+            region: String::from("{{job_id}}"),
+            index_type: String::from("admins"),
+            version: String::from("0.1.0"),
+            state,
+        }
+    }
+
+    fn available() -> State {
+        State::Available {
+            index: String::from("munin_admin_fr_1"),
+            alias: String::from("munin_admin_fr"),
+        }
+    }
+
+    // A receiver which replies to each request with the next status, and sends us the bodies.
+    fn receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (bodies, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let mut header = line.splitn(2, ':');
+                    let name = header.next().unwrap_or_default();
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = header.next().unwrap_or_default().trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.send(String::from_utf8(body).unwrap()).unwrap();
+                let reply = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                reader.get_mut().write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[test]
+    fn posts_envelope_without_template() {
+        let (url, received) = receiver(vec![200]);
+        let webhook = Webhook::new(&url).unwrap();
+        let envelope = envelope(available());
+        webhook.deliver(&webhook.body(&envelope).unwrap()).unwrap();
+        let posted: Envelope = serde_json::from_str(&received.recv().unwrap()).unwrap();
+        assert_eq!(posted, envelope);
+    }
+
+    #[test]
+    fn fills_template_in_one_pass() {
+        let template = r#"{"text": "{{job_id}} in {{region}}: {{state}}, {{unknown}}",
+                           "job": "{{envelope}}", "count": 1}"#;
+        let webhook = Webhook::new("http://localhost/")
+            .unwrap()
+            .template(template)
+            .unwrap();
+        let envelope = envelope(available());
+        let body: serde_json::Value =
+            serde_json::from_str(&webhook.body(&envelope).unwrap()).unwrap();
+        // Values which look like placeholders are not replaced again
+        assert_eq!(
+            body["text"],
+            "job-{{region}} in {{job_id}}: Available, {{unknown}}"
+        );
+        assert_eq!(body["job"], serde_json::to_value(&envelope).unwrap());
+        assert_eq!(body["count"], 1);
+    }
+
+    #[test]
+    fn rejects_envelope_inside_string() {
+        let webhook = Webhook::new("http://localhost/").unwrap();
+        assert!(webhook
+            .clone()
+            .template(r#"{"text": "{{envelope}}!"}"#)
+            .is_err());
+        assert!(webhook
+            .clone()
+            .template(r#"{"job": {{envelope}}}"#)
+            .is_err());
+        assert!(webhook.template(r#"{"job": ["{{envelope}}"]}"#).is_ok());
+    }
+
+    #[test]
+    fn posts_again_after_server_error() {
+        let (url, received) = receiver(vec![500, 500, 204]);
+        let mut webhook = Webhook::new(&url).unwrap().attempts(3);
+        webhook.delay = Duration::from_millis(10);
+        webhook.deliver("{}").unwrap();
+        assert_eq!(received.iter().take(3).count(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_attempts() {
+        let (url, received) = receiver(vec![500, 500]);
+        let mut webhook = Webhook::new(&url).unwrap().attempts(2);
+        webhook.delay = Duration::from_millis(10);
+        assert!(webhook.deliver("{}").is_err());
+        assert_eq!(received.iter().count(), 2);
+    }
+
+    #[test]
+    fn triggers_on_chosen_states() {
+        let webhook = Webhook::new("http://localhost/").unwrap();
+        assert!(webhook.triggers(&available()));
+        assert!(webhook.triggers(&State::Failure(String::from("oops"))));
+        assert!(!webhook.triggers(&State::NotAvailable));
+
+        let webhook = webhook.states(&["cancelled"]).unwrap();
+        assert!(webhook.triggers(&State::Cancelled));
+        assert!(!webhook.triggers(&available()));
+        assert!(Webhook::new("http://localhost/")
+            .unwrap()
+            .states(&["Sleeping"])
+            .is_err());
+    }
+}